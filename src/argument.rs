@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+// a placeholder in exec, positional ones are named by their index
+#[derive(Debug, Clone)]
+pub(crate) struct Argument {
+    pub(crate) name: String,
+    pub(crate) pattern: Option<Regex>,
+    pub(crate) rule: Option<Rule>,
+    pub(crate) default: Option<String>,
+    pub(crate) required: bool,
+}
+
+impl Argument {
+    pub(crate) fn new(name: &str, pattern: Option<Regex>, rule: Option<Rule>) -> Self {
+        Argument {
+            name: name.to_string(),
+            pattern: pattern,
+            rule: rule,
+            default: None,
+            required: true,
+        }
+    }
+
+    pub(crate) fn index(self: &Self) -> Option<usize> {
+        self.name.parse().ok()
+    }
+
+    // config key of the argument: arg0 for positional, arg.name for named
+    // and args for the variadic ${@}
+    pub(crate) fn key(name: &str) -> String {
+        if name == "@" {
+            return "args".to_string();
+        }
+        match name.parse::<usize>() {
+            Ok(_) => format!("arg{}", name),
+            Err(_) => format!("arg.{}", name),
+        }
+    }
+
+    // returns the value to use, which is normalized by some rules
+    pub(crate) fn check(self: &Self, value: String) -> Result<String> {
+        // NOTE: allow empty argument, typed ones only when optional
+        if value == "" && (self.rule.is_none() || !self.required) {
+            return Ok(value);
+        }
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(&value) {
+                return Err(anyhow!(
+                    "Illegal Argument: {} for {}: not match {}",
+                    value,
+                    self.name,
+                    pattern
+                ));
+            }
+        }
+        match &self.rule {
+            None => Ok(value),
+            Some(rule) => rule
+                .check(&value)
+                .map_err(|e| anyhow!("Illegal Argument: {} for {}: {}", value, self.name, e)),
+        }
+    }
+}
+
+// values of ${@}, each of them is checked by arg
+#[derive(Debug, Clone)]
+pub(crate) struct Variadic {
+    pub(crate) arg: Argument,
+    pub(crate) min: usize,
+    pub(crate) max: Option<usize>,
+}
+
+// typed validation declared by the .type key of an argument
+#[derive(Debug, Clone)]
+pub(crate) enum Rule {
+    Int { min: Option<i64>, max: Option<i64> },
+    Enum(Vec<String>),
+    // canonical root the path must live under
+    Path(PathBuf),
+    // in seconds
+    Duration { min: Option<u64>, max: Option<u64> },
+    Ip,
+    Cidr,
+}
+
+impl Rule {
+    pub(crate) fn parse(key: &str, prop: &HashMap<String, String>) -> Result<Option<Self>> {
+        let get = |k: &str| prop.get(format!("{}.{}", key, k).as_str());
+        let t = match get("type") {
+            None => return Ok(None),
+            Some(t) => t,
+        };
+        let rule = match t.as_str() {
+            "int" => Rule::Int {
+                min: get("min").map(|v| v.parse()).transpose()?,
+                max: get("max").map(|v| v.parse()).transpose()?,
+            },
+            "enum" => Rule::Enum(
+                get("choices")
+                    .ok_or(anyhow!("{}.choices not found", key))?
+                    .split(",")
+                    .map(|c| c.trim().to_string())
+                    .collect(),
+            ),
+            "path" => {
+                let root = get("root").ok_or(anyhow!("{}.root not found", key))?;
+                Rule::Path(
+                    fs::canonicalize(root).map_err(|e| anyhow!("{}.root {}: {}", key, root, e))?,
+                )
+            }
+            "duration" => Rule::Duration {
+                min: get("min").map(|v| parse_duration(v)).transpose()?,
+                max: get("max").map(|v| parse_duration(v)).transpose()?,
+            },
+            "ip" => Rule::Ip,
+            "cidr" => Rule::Cidr,
+            _ => return Err(anyhow!("unknown {}.type: {}", key, t)),
+        };
+        Ok(Some(rule))
+    }
+
+    // returns the value to use, or why it is refused
+    fn check(self: &Self, value: &str) -> Result<String, String> {
+        match self {
+            Rule::Int { min, max } => {
+                let v: i64 = value.parse().map_err(|_| "not an int".to_string())?;
+                check_range(v, *min, *max, "int")?;
+            }
+            Rule::Enum(choices) => {
+                if !choices.iter().any(|c| c == value) {
+                    return Err(format!("not one of {}", choices.join(", ")));
+                }
+            }
+            Rule::Path(root) => {
+                // NOTE: symlinks are resolved before checking the root, and the
+                // resolved path is passed on so it can not be swapped later
+                let path = fs::canonicalize(root.join(value))
+                    .map_err(|e| format!("invalid path: {}", e))?;
+                if !path.starts_with(root) {
+                    return Err(format!("path not under {}", root.display()));
+                }
+                return path
+                    .to_str()
+                    .map(|p| p.to_string())
+                    .ok_or("path not utf-8".to_string());
+            }
+            Rule::Duration { min, max } => {
+                let v = parse_duration(value).map_err(|_| "not a duration".to_string())?;
+                check_range(v, *min, *max, "duration")?;
+            }
+            Rule::Ip => {
+                value
+                    .parse::<IpAddr>()
+                    .map_err(|_| "not an ip address".to_string())?;
+            }
+            Rule::Cidr => {
+                parse_cidr(value).ok_or("not a cidr".to_string())?;
+            }
+        }
+        Ok(value.to_string())
+    }
+}
+
+fn check_range<T: PartialOrd + std::fmt::Display>(
+    v: T,
+    min: Option<T>,
+    max: Option<T>,
+    name: &str,
+) -> Result<(), String> {
+    if let Some(min) = min {
+        if v < min {
+            return Err(format!("{} less than {}", name, min));
+        }
+    }
+    if let Some(max) = max {
+        if v > max {
+            return Err(format!("{} greater than {}", name, max));
+        }
+    }
+    Ok(())
+}
+
+// seconds with an optional unit: 30, 30s, 5m, 2h, 1d
+fn parse_duration(value: &str) -> Result<u64> {
+    let (num, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        None => (value, "s"),
+        Some(i) => value.split_at(i),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(anyhow!("Invalid duration: {}", value)),
+    };
+    let num: u64 = num.parse()?;
+    num.checked_mul(scale).ok_or(anyhow!("Invalid duration: {}", value))
+}
+
+fn parse_cidr(value: &str) -> Option<(IpAddr, u8)> {
+    let mut parts = value.splitn(2, "/");
+    let addr: IpAddr = parts.next()?.parse().ok()?;
+    let prefix: u8 = parts.next()?.parse().ok()?;
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix > bits {
+        return None;
+    }
+    Some((addr, prefix))
+}
+
+// positional arguments must be 0..n, optional ones only at the end
+pub(crate) fn check_positional(args: &mut Vec<Argument>) -> Result<()> {
+    args.sort_by_key(|a| a.index().unwrap_or(usize::MAX));
+    let mut optional = false;
+    for (i, arg) in args.iter().filter(|a| a.index().is_some()).enumerate() {
+        if arg.index() != Some(i) {
+            return Err(anyhow!("arg{} not used in exec", i));
+        }
+        if !arg.required {
+            optional = true;
+        } else if optional {
+            return Err(anyhow!("required arg{} after optional ones", i));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn arg_regex(pattern: &str, full_match: bool) -> Result<Regex, regex::Error> {
+    if full_match {
+        Regex::new(&format!("^(?:{})$", pattern))
+    } else {
+        Regex::new(pattern)
+    }
+}
+
+pub(crate) fn is_anchored(pattern: &str) -> bool {
+    pattern.starts_with('^') && pattern.ends_with('$') && !pattern.ends_with("\\$")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(t: &str, extra: &[(&str, &str)]) -> Argument {
+        let mut prop: HashMap<String, String> = HashMap::new();
+        prop.insert("arg0.type".to_string(), t.to_string());
+        for (k, v) in extra {
+            prop.insert(format!("arg0.{}", k), v.to_string());
+        }
+        Argument::new("0", None, Rule::parse("arg0", &prop).unwrap())
+    }
+
+    #[test]
+    fn test_arg_regex_full_match() {
+        let re = arg_regex(r"\d+", true).unwrap();
+        assert!(re.is_match("42"));
+        assert!(!re.is_match("1; rm -rf /"));
+        let re = arg_regex(r"a|ab", true).unwrap();
+        assert!(re.is_match("ab"));
+        let re = arg_regex(r"\d+", false).unwrap();
+        assert!(re.is_match("1; rm -rf /"));
+
+        assert!(is_anchored(r"^\d+$"));
+        assert!(!is_anchored(r"\d+$"));
+        assert!(!is_anchored(r"^\d+\$"));
+    }
+
+    #[test]
+    fn test_typed_arguments() {
+        let port = typed("int", &[("min", "1"), ("max", "65535")]);
+        assert_eq!(port.check("80".to_string()).unwrap(), "80");
+        assert!(port.check("0".to_string()).is_err());
+        assert!(port.check("80; ls".to_string()).is_err());
+        assert!(port.check("".to_string()).is_err());
+
+        let action = typed("enum", &[("choices", "start, stop")]);
+        assert!(action.check("stop".to_string()).is_ok());
+        let err = action.check("kill".to_string()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Illegal Argument: kill for 0: not one of start, stop"
+        );
+
+        let since = typed("duration", &[("max", "1h")]);
+        assert!(since.check("30m".to_string()).is_ok());
+        assert!(since.check("2h".to_string()).is_err());
+        assert!(since.check("99999999999999999d".to_string()).is_err());
+
+        let addr = typed("cidr", &[]);
+        assert!(addr.check("10.0.0.0/8".to_string()).is_ok());
+        assert!(addr.check("10.0.0.0/33".to_string()).is_err());
+        assert!(typed("ip", &[]).check("::1".to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_path_argument() {
+        let root = std::env::temp_dir().join(format!("redarrow-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("logs")).unwrap();
+        fs::write(root.join("logs/app.log"), "").unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("logs/etc")).unwrap();
+
+        let log = typed("path", &[("root", root.join("logs").to_str().unwrap())]);
+        let real = fs::canonicalize(root.join("logs/app.log")).unwrap();
+        assert_eq!(
+            log.check("app.log".to_string()).unwrap(),
+            real.to_str().unwrap()
+        );
+        assert!(log.check("../logs/app.log".to_string()).is_ok());
+        assert!(log.check("etc/passwd".to_string()).is_err());
+        assert!(log.check("/etc/passwd".to_string()).is_err());
+        assert!(log.check("missing.log".to_string()).is_err());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
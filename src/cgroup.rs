@@ -0,0 +1,168 @@
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use nix::sys::signal;
+use nix::unistd::Pid;
+
+lazy_static! {
+    // cgroup v2 directory holding a child group for each run
+    static ref ROOT: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+// makes group names unique within the process
+static SEQ: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn set_root(root: &Path) -> Result<()> {
+    let controllers = fs::read_to_string(root.join("cgroup.controllers"))
+        .map_err(|e| anyhow!("{} is not a cgroup v2 directory: {}", root.display(), e))?;
+    // NOTE: memory.peak and detailed cpu.stat need the controllers enabled
+    // for the children
+    for c in ["memory", "cpu"].iter() {
+        if !controllers.split_whitespace().any(|a| a == *c) {
+            log::warn!("cgroup controller {} not available in {}", c, root.display());
+            continue;
+        }
+        if let Err(e) = fs::write(root.join("cgroup.subtree_control"), format!("+{}", c)) {
+            log::warn!("enable cgroup controller {} in {}: {}", c, root.display(), e);
+        }
+    }
+    let probe = root.join(format!("redarrow-probe-{}", process::id()));
+    fs::create_dir(&probe).map_err(|e| anyhow!("{} not writable: {}", root.display(), e))?;
+    fs::remove_dir(&probe)?;
+
+    let mut r = ROOT.write().unwrap_or_else(|e| e.into_inner());
+    *r = Some(root.to_path_buf());
+    Ok(())
+}
+
+// a child group for a single run, removed when dropped unless processes the
+// command left running on purpose are still in it
+pub(crate) struct Cgroup {
+    path: PathBuf,
+    pub(crate) procs: CString,
+    // killed processes leave the group asynchronously, so removing it waits
+    killed: AtomicBool,
+}
+
+impl Cgroup {
+    // None when no cgroup root is configured
+    pub(crate) fn create(name: &str) -> Result<Option<Self>> {
+        let root = match &*ROOT.read().unwrap_or_else(|e| e.into_inner()) {
+            None => return Ok(None),
+            Some(r) => r.clone(),
+        };
+        let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        // NOTE: section names may hold anything, like a /
+        let name: String = name
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                true => c,
+                false => '_',
+            })
+            .collect();
+        let path = root.join(format!("{}-{}-{}", name, process::id(), seq));
+        fs::create_dir(&path)
+            .map_err(|e| anyhow!("Create cgroup {} error: {}", path.display(), e))?;
+        let procs = CString::new(path.join("cgroup.procs").as_os_str().as_bytes())?;
+        Ok(Some(Cgroup {
+            path: path,
+            procs: procs,
+            killed: AtomicBool::new(false),
+        }))
+    }
+
+    // kill every process left in the group, including ones which left the
+    // process group with setsid
+    pub(crate) fn kill(self: &Self) -> io::Result<()> {
+        self.killed.store(true, Ordering::Relaxed);
+        match fs::write(self.path.join("cgroup.kill"), "1") {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            r => return r,
+        }
+        // NOTE: cgroup.kill needs linux 5.14, freeze so nothing forks meanwhile
+        fs::write(self.path.join("cgroup.freeze"), "1")?;
+        for pid in fs::read_to_string(self.path.join("cgroup.procs"))?.lines() {
+            if let Ok(pid) = pid.parse() {
+                let _ = signal::kill(Pid::from_raw(pid), signal::SIGKILL);
+            }
+        }
+        fs::write(self.path.join("cgroup.freeze"), "0")
+    }
+
+    // peak memory in bytes and cpu time in seconds, when available
+    pub(crate) fn usage(self: &Self) -> (Option<u64>, Option<f64>) {
+        let memory_peak = fs::read_to_string(self.path.join("memory.peak"))
+            .ok()
+            .and_then(|v| v.trim().parse().ok());
+        let cpu_time = fs::read_to_string(self.path.join("cpu.stat"))
+            .ok()
+            .and_then(|stat| {
+                stat.lines()
+                    .find_map(|l| l.strip_prefix("usage_usec "))
+                    .and_then(|v| v.parse::<u64>().ok())
+            })
+            .map(|usec| usec as f64 / 1e6);
+        (memory_peak, cpu_time)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        let path = self.path.clone();
+        let killed = self.killed.load(Ordering::Relaxed);
+        // NOTE: waiting for the group to empty must not block the runtime
+        match tokio::runtime::Handle::try_current() {
+            Ok(rt) => drop(rt.spawn_blocking(move || remove(&path, killed))),
+            Err(_) => remove(&path, killed),
+        }
+    }
+}
+
+// remove a group once it is empty, daemons started by a command which exited
+// by itself keep theirs
+fn remove(path: &Path, killed: bool) {
+    for _ in 0..20 {
+        match fs::remove_dir(path) {
+            Ok(()) => return,
+            Err(_) if !killed && populated(path) => {
+                log::info!("cgroup {} left for processes still running", path.display());
+                return;
+            }
+            Err(_) => thread::sleep(Duration::from_millis(5)),
+        }
+    }
+    log::warn!("remove cgroup {} failed", path.display());
+}
+
+fn populated(path: &Path) -> bool {
+    fs::read_to_string(path.join("cgroup.events"))
+        .map_or(false, |events| events.lines().any(|l| l == "populated 1"))
+}
+
+// move the calling process into the group, called in pre_exec so it only
+// uses syscalls
+pub(crate) fn enter(procs: &CStr) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ret = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+        let err = io::Error::last_os_error();
+        libc::close(fd);
+        if ret < 0 {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
@@ -17,6 +17,13 @@ struct ClientArgs {
     #[argh(positional)]
     arguments: Vec<String>,
 
+    #[argh(
+        option,
+        short = 'n',
+        description = "named argument of the command as name=value, repeatable"
+    )]
+    named: Vec<String>,
+
     #[argh(switch, description = "output the detail information of running")]
     detail: bool,
 
@@ -44,9 +51,20 @@ fn main() {
     std::process::exit(exit_code);
 }
 
+fn parse_named(named: &Vec<String>) -> HashMap<String, String> {
+    named
+        .iter()
+        .map(|n| match n.find("=") {
+            None => (n.to_string(), "".to_string()),
+            Some(i) => (n[..i].to_string(), n[i + 1..].to_string()),
+        })
+        .collect()
+}
+
 fn run_single(args: ClientArgs) -> i32 {
     let mut client = Client::new(args.host, args.port, args.command, args.arguments);
     client.set_user_agent("Redarrow-client");
+    client.set_named_arguments(parse_named(&args.named));
     let (tx, rx) = mpsc::channel::<(i8, Vec<u8>)>();
     let child = thread::Builder::new()
         .name("output printer".to_string())
@@ -104,6 +122,7 @@ fn run_parallel(args: ClientArgs) -> i32 {
             args.arguments.clone(),
         );
         client.set_user_agent("Redarrow-client");
+        client.set_named_arguments(parse_named(&args.named));
         let rt = Runtime::new().unwrap();
         let child = thread::Builder::new()
             .name(format!("runner on {}", host))
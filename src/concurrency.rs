@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGauge, IntGaugeVec};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Instant};
+
+lazy_static! {
+    // slots shared by all commands, with their number
+    static ref GLOBAL: RwLock<Option<(Arc<Semaphore>, usize)>> = RwLock::new(None);
+    // slots of each command by name, kept over config reloads so runs of the
+    // old config still count
+    static ref SLOTS: Mutex<HashMap<String, Arc<Slots>>> = Mutex::new(HashMap::new());
+    static ref QUEUED: IntGaugeVec =
+        register_int_gauge_vec!("redarrow_commands_queued", "redarrow requests waiting for a free slot", &["command"]).unwrap();
+    static ref IN_FLIGHT: IntGaugeVec =
+        register_int_gauge_vec!("redarrow_commands_in_flight", "redarrow commands running", &["command"]).unwrap();
+}
+
+pub(crate) fn set_global(max: usize) -> Result<()> {
+    if max == 0 {
+        return Err(anyhow!("max concurrency must be positive"));
+    }
+    let mut g = GLOBAL.write().unwrap_or_else(|e| e.into_inner());
+    *g = Some((Arc::new(Semaphore::new(max)), max));
+    Ok(())
+}
+
+// slots of a command, resized in place when its max_concurrency changes
+#[derive(Debug)]
+struct Slots {
+    semaphore: Arc<Semaphore>,
+    size: Mutex<Size>,
+}
+
+#[derive(Debug)]
+struct Size {
+    max: usize,
+    // permits to take away once released, after lowering max while they
+    // were held
+    excess: usize,
+}
+
+impl Slots {
+    // slots of a command, created with max if it has none yet, resized to
+    // max when resize is set
+    fn get(name: &str, max: usize, resize: bool) -> Arc<Self> {
+        let mut slots = SLOTS.lock().unwrap_or_else(|e| e.into_inner());
+        match slots.get(name) {
+            Some(s) => {
+                if resize {
+                    s.resize(max);
+                }
+                s.clone()
+            }
+            None => {
+                let s = Arc::new(Slots {
+                    semaphore: Arc::new(Semaphore::new(max)),
+                    size: Mutex::new(Size { max: max, excess: 0 }),
+                });
+                slots.insert(name.to_string(), s.clone());
+                s
+            }
+        }
+    }
+
+    fn resize(self: &Self, max: usize) {
+        let mut size = self.size.lock().unwrap_or_else(|e| e.into_inner());
+        if max > size.max {
+            let more = max - size.max;
+            let kept = more.min(size.excess);
+            size.excess -= kept;
+            self.semaphore.add_permits(more - kept);
+        } else {
+            let mut less = size.max - max;
+            while less > 0 {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                less -= 1;
+            }
+            size.excess += less;
+        }
+        size.max = max;
+    }
+
+    fn max(self: &Self) -> usize {
+        self.size.lock().unwrap_or_else(|e| e.into_inner()).max
+    }
+
+    fn release(self: &Self, permit: OwnedSemaphorePermit) {
+        let mut size = self.size.lock().unwrap_or_else(|e| e.into_inner());
+        if size.excess > 0 {
+            size.excess -= 1;
+            permit.forget();
+        }
+    }
+}
+
+// runs of a command at the same time, requests beyond wait up to
+// queue_timeout for a free slot
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Limiter {
+    max: Option<usize>,
+    queue_timeout: Duration,
+}
+
+impl Limiter {
+    pub(crate) fn parse(prop: &HashMap<String, String>) -> Result<Self> {
+        let mut l = Limiter::default();
+        if let Some(max) = prop.get("max_concurrency") {
+            let max: usize = max.parse()?;
+            if max == 0 {
+                return Err(anyhow!("max_concurrency must be positive"));
+            }
+            l.max = Some(max);
+        }
+        if let Some(timeout) = prop.get("queue_timeout") {
+            l.queue_timeout = Duration::from_secs(timeout.parse()?);
+        }
+        Ok(l)
+    }
+
+    // resize the slots of the command once its config is served, so a
+    // config which is never served does not change them
+    pub(crate) fn apply(self: &Self, name: &str) {
+        if let Some(max) = self.max {
+            Slots::get(name, max, true);
+        }
+    }
+
+    // a slot of the command, then one of the server
+    pub(crate) async fn acquire(self: &Self, name: &str) -> std::result::Result<Slot, Busy> {
+        let queued = QUEUED.with_label_values(&[name]);
+        let deadline = Instant::now() + self.queue_timeout;
+        let command = match self.max.map(|max| Slots::get(name, max, false)) {
+            None => None,
+            Some(slots) => match wait(&slots.semaphore, deadline, &queued).await {
+                Some(permit) => Some((slots.clone(), permit)),
+                None => return Err(Busy::Command(slots.max())),
+            },
+        };
+        let global = GLOBAL.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let global = match global {
+            None => None,
+            Some((slots, max)) => match wait(&slots, deadline, &queued).await {
+                Some(permit) => Some(permit),
+                None => return Err(Busy::Server(max)),
+            },
+        };
+        let in_flight = IN_FLIGHT.with_label_values(&[name]);
+        in_flight.inc();
+        Ok(Slot {
+            command: command,
+            _global: global,
+            in_flight: in_flight,
+        })
+    }
+}
+
+async fn wait(
+    slots: &Arc<Semaphore>,
+    deadline: Instant,
+    queued: &IntGauge,
+) -> Option<OwnedSemaphorePermit> {
+    if let Ok(permit) = slots.clone().try_acquire_owned() {
+        return Some(permit);
+    }
+    // NOTE: a guard, as the request goes away when the client disconnects
+    let _waiting = Waiting::new(queued);
+    time::timeout_at(deadline, slots.clone().acquire_owned()).await.ok()?.ok()
+}
+
+struct Waiting(IntGauge);
+
+impl Waiting {
+    fn new(queued: &IntGauge) -> Self {
+        queued.inc();
+        Waiting(queued.clone())
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// held while a command runs, frees its slots when dropped
+#[derive(Debug)]
+pub struct Slot {
+    command: Option<(Arc<Slots>, OwnedSemaphorePermit)>,
+    _global: Option<OwnedSemaphorePermit>,
+    in_flight: IntGauge,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some((slots, permit)) = self.command.take() {
+            slots.release(permit);
+        }
+        self.in_flight.dec();
+    }
+}
+
+// no free slot within queue_timeout, with the max_concurrency reached
+#[derive(Debug)]
+pub enum Busy {
+    Command(usize),
+    Server(usize),
+}
+
+impl Busy {
+    // name of the limit in the command result
+    pub fn limit(self: &Self) -> &str {
+        match self {
+            Busy::Command(_) => "max_concurrency",
+            Busy::Server(_) => "server_max_concurrency",
+        }
+    }
+}
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Busy::Command(max) => write!(f, "Too Many Requests: max_concurrency {} reached", max),
+            Busy::Server(max) => write!(f, "Server Busy: max_concurrency {} of the server reached", max),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limiter() {
+        let mut prop: HashMap<String, String> = HashMap::new();
+        prop.insert("max_concurrency".to_string(), "1".to_string());
+        let limiter = Limiter::parse(&prop).unwrap();
+        let slot = limiter.acquire("test-limiter").await.unwrap();
+        assert_eq!(IN_FLIGHT.with_label_values(&["test-limiter"]).get(), 1);
+        assert!(matches!(limiter.acquire("test-limiter").await, Err(Busy::Command(1))));
+
+        let limiter = Limiter {
+            queue_timeout: Duration::from_secs(5),
+            ..limiter
+        };
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire("test-limiter").await.is_ok() }
+        });
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(QUEUED.with_label_values(&["test-limiter"]).get(), 1);
+        drop(slot);
+        assert!(waiting.await.unwrap());
+        assert_eq!(QUEUED.with_label_values(&["test-limiter"]).get(), 0);
+        assert_eq!(IN_FLIGHT.with_label_values(&["test-limiter"]).get(), 0);
+
+        prop.insert("max_concurrency".to_string(), "0".to_string());
+        assert!(Limiter::parse(&prop).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_limiter_reload() {
+        let mut prop: HashMap<String, String> = HashMap::new();
+        prop.insert("max_concurrency".to_string(), "2".to_string());
+        let limiter = Limiter::parse(&prop).unwrap();
+        limiter.apply("test-reload");
+        let first = limiter.acquire("test-reload").await.unwrap();
+        let second = limiter.acquire("test-reload").await.unwrap();
+
+        // runs of the old config still count
+        let limiter = Limiter::parse(&prop).unwrap();
+        limiter.apply("test-reload");
+        assert!(limiter.acquire("test-reload").await.is_err());
+
+        prop.insert("max_concurrency".to_string(), "1".to_string());
+        let limiter = Limiter::parse(&prop).unwrap();
+        limiter.apply("test-reload");
+        drop(first);
+        assert!(matches!(limiter.acquire("test-reload").await, Err(Busy::Command(1))));
+        drop(second);
+        let third = limiter.acquire("test-reload").await.unwrap();
+
+        prop.insert("max_concurrency".to_string(), "3".to_string());
+        let limiter = Limiter::parse(&prop).unwrap();
+        // NOTE: parsed but not applied, like a config whose reload failed
+        assert!(matches!(limiter.acquire("test-reload").await, Err(Busy::Command(1))));
+        limiter.apply("test-reload");
+        let _fourth = limiter.acquire("test-reload").await.unwrap();
+        let _fifth = limiter.acquire("test-reload").await.unwrap();
+        assert!(limiter.acquire("test-reload").await.is_err());
+        drop(third);
+        assert!(limiter.acquire("test-reload").await.is_ok());
+    }
+}
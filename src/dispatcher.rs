@@ -1,29 +1,64 @@
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read};
-use std::os::unix::process::CommandExt;
-use std::path::Path;
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::convert::TryFrom;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::{Arc, Mutex};
-use std::task::Waker;
-use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
 use glob::glob;
 use ini::Ini;
 use nix::sys::signal;
-use nix::unistd::{setsid, Pid};
+use nix::unistd::{
+    chown, getgrouplist, setgid, setgroups, setsid, setuid, Gid, Group, Pid, Uid, User,
+};
 use regex::{Captures, Regex};
-use wait_timeout::ChildExt;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+use tokio::time;
 use prometheus::{
     IntCounterVec
 };
 use prometheus::{register_int_counter_vec};
 use lazy_static::lazy_static;
 
+use crate::argument::{arg_regex, check_positional, is_anchored, Argument, Rule, Variadic};
+use crate::cgroup::{self, Cgroup};
+use crate::concurrency::{self, Limiter};
+use crate::hardening::Hardening;
+use crate::landlock::Landlock;
+use crate::reaper::{self, Descendants};
+use crate::sandbox::{Sandbox, Setup};
 use crate::CommandResult;
 
-static RE_ARGS: &str = r"\$\{(\d+)\}";
+pub use crate::concurrency::{Busy, Slot};
+
+// ${0} for positional and ${name} for named arguments
+static RE_ARGS: &str = r"\$\{(\w+)\}";
+// expands to all remaining positional arguments
+static VARIADIC: &str = "${@}";
+// prefix of the variables holding arguments in shell commands
+static SHELL_VAR: &str = "REDARROW_ARG_";
+// placeholders and ${@} in a shell command
+static RE_SHELL_ARGS: &str = r"\$\{(\w+|@)\}";
+
+// PATH of commands with a cleared environment
+static DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+// makes script file names unique within the process
+static SCRIPT_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+// longest part of a line buffered for a chunked response, longer lines are
+// sent in parts
+const MAX_LINE: usize = 64 * 1024;
 
 pub type Configs = HashMap<String, Command>;
 
@@ -31,44 +66,369 @@ pub type Configs = HashMap<String, Command>;
 lazy_static! {
     pub static ref COMMANDS: IntCounterVec  =
         register_int_counter_vec!("redarrow_commands_total", "redarrow commands total count", &["status", "code"]).unwrap();
+    pub static ref CONFIG_RELOADS: IntCounterVec  =
+        register_int_counter_vec!("redarrow_config_reloads_total", "redarrow config reloads total count", &["status"]).unwrap();
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Command {
     name: String,
     exec: String,
-    args: Vec<Regex>,
+    args: Vec<Argument>,
+    variadic: Option<Variadic>,
+    shell: bool,
+    script: Option<Script>,
+    env: Environment,
+    credential: Option<Credential>,
+    limits: Vec<ResourceLimit>,
+    sandbox: Option<Sandbox>,
+    hardening: Option<Hardening>,
+    landlock: Option<Landlock>,
+    limiter: Limiter,
     time_limit: u64,
+    max_output_bytes: Option<usize>,
+    kill: KillPolicy,
+}
+
+// an inline script, passed to the interpreter right after its own arguments
+#[derive(Debug, Clone)]
+struct Script {
+    body: String,
+    position: usize,
+}
+
+// working directory and environment of a command, inherited from the server
+// unless configured
+#[derive(Debug, Clone, Default)]
+struct Environment {
+    cwd: Option<PathBuf>,
+    // start from an empty environment, keeping only passthrough
+    clear: bool,
+    passthrough: Vec<String>,
+    path: Option<String>,
+    vars: Vec<(String, String)>,
+}
+
+impl Environment {
+    fn parse(prop: &HashMap<String, String>) -> Result<Self> {
+        let mut env = Environment::default();
+        if let Some(cwd) = prop.get("cwd") {
+            let cwd = PathBuf::from(cwd);
+            if !cwd.is_dir() {
+                return Err(anyhow!("cwd {} is not a directory", cwd.display()));
+            }
+            env.cwd = Some(cwd);
+        }
+        if let Some(clear) = prop.get("env_clear") {
+            env.clear = parse_bool(clear)?;
+        }
+        if let Some(names) = prop.get("env_passthrough") {
+            env.passthrough = names
+                .split(",")
+                .map(|n| n.trim().to_string())
+                .filter(|n| n != "")
+                .collect();
+        }
+        env.path = prop.get("path").map(|p| p.to_string());
+        if env.clear && env.path.is_none() {
+            env.path = Some(DEFAULT_PATH.to_string());
+        }
+        for (key, value) in prop.iter() {
+            if let Some(name) = key.strip_prefix("env.") {
+                env.vars.push((name.to_string(), value.to_string()));
+            }
+        }
+        env.vars.sort();
+        Ok(env)
+    }
+
+    fn apply(self: &Self, command: &mut process::Command) {
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        if self.clear {
+            command.env_clear();
+            for name in self.passthrough.iter() {
+                if let Some(value) = env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        if let Some(path) = &self.path {
+            command.env("PATH", path);
+        }
+        for (name, value) in self.vars.iter() {
+            command.env(name, value);
+        }
+    }
+}
+
+// user and groups a command runs as, switched to in pre_exec
+#[derive(Debug, Clone)]
+struct Credential {
+    uid: Option<Uid>,
+    gid: Gid,
+    groups: Vec<Gid>,
+}
+
+impl Credential {
+    fn parse(prop: &HashMap<String, String>) -> Result<Option<Self>> {
+        let user = match prop.get("user") {
+            None => None,
+            Some(u) => {
+                let user = match u.parse() {
+                    Ok(uid) => User::from_uid(Uid::from_raw(uid))?,
+                    Err(_) => User::from_name(u)?,
+                };
+                Some(user.ok_or(anyhow!("user {} not found", u))?)
+            }
+        };
+        let gid = match (prop.get("group"), &user) {
+            (Some(g), _) => lookup_group(g)?,
+            (None, Some(u)) => u.gid,
+            (None, None) if prop.contains_key("groups") => {
+                return Err(anyhow!("groups without user or group"));
+            }
+            (None, None) => return Ok(None),
+        };
+        // NOTE: supplementary groups of the server must never be kept
+        let groups = match (prop.get("groups"), &user) {
+            (Some(list), _) => list
+                .split(",")
+                .map(|g| g.trim())
+                .filter(|g| *g != "")
+                .map(lookup_group)
+                .collect::<Result<Vec<Gid>>>()?,
+            (None, Some(u)) => getgrouplist(&CString::new(u.name.as_str())?, gid)?,
+            (None, None) => vec![gid],
+        };
+        Ok(Some(Credential {
+            uid: user.map(|u| u.uid),
+            gid: gid,
+            groups: groups,
+        }))
+    }
+
+    fn switch(self: &Self) -> nix::Result<()> {
+        setgroups(&self.groups)?;
+        setgid(self.gid)?;
+        if let Some(uid) = self.uid {
+            setuid(uid)?;
+        }
+        Ok(())
+    }
+}
+
+fn lookup_group(name: &str) -> Result<Gid> {
+    let group = match name.parse() {
+        Ok(gid) => Group::from_gid(Gid::from_raw(gid))?,
+        Err(_) => Group::from_name(name)?,
+    };
+    Ok(group.ok_or(anyhow!("group {} not found", name))?.gid)
+}
+
+// setrlimit applied in pre_exec, configured by rlimit.<name>
+#[derive(Debug, Clone, Copy)]
+struct ResourceLimit {
+    resource: Resource,
+    value: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resource {
+    // bytes
+    AddressSpace,
+    // seconds
+    Cpu,
+    OpenFiles,
+    // per user, not enforced for root
+    Processes,
+    // bytes
+    Core,
+    // bytes
+    FileSize,
+}
+
+impl ResourceLimit {
+    fn parse(prop: &HashMap<String, String>) -> Result<Vec<Self>> {
+        let mut limits = Vec::new();
+        for (key, resource) in [
+            ("as", Resource::AddressSpace),
+            ("cpu", Resource::Cpu),
+            ("nofile", Resource::OpenFiles),
+            ("nproc", Resource::Processes),
+            ("core", Resource::Core),
+            ("fsize", Resource::FileSize),
+        ]
+        .iter()
+        {
+            let key = format!("rlimit.{}", key);
+            let value = match prop.get(&key) {
+                None => continue,
+                Some(v) => v,
+            };
+            let value = match resource {
+                Resource::AddressSpace | Resource::Core | Resource::FileSize => parse_size(value),
+                _ => value.parse().map_err(|e| anyhow!("{}", e)),
+            };
+            limits.push(ResourceLimit {
+                resource: *resource,
+                value: value.map_err(|e| anyhow!("{}: {}", key, e))?,
+            });
+        }
+        Ok(limits)
+    }
+
+    fn apply(self: &Self) -> std::io::Result<()> {
+        // NOTE: a higher hard cpu limit lets SIGXCPU arrive before SIGKILL
+        let rlim = libc::rlimit {
+            rlim_cur: self.value as libc::rlim_t,
+            rlim_max: match self.resource {
+                Resource::Cpu => self.value.saturating_add(1),
+                _ => self.value,
+            } as libc::rlim_t,
+        };
+        let ret = unsafe {
+            match self.resource {
+                Resource::AddressSpace => libc::setrlimit(libc::RLIMIT_AS, &rlim),
+                Resource::Cpu => libc::setrlimit(libc::RLIMIT_CPU, &rlim),
+                Resource::OpenFiles => libc::setrlimit(libc::RLIMIT_NOFILE, &rlim),
+                Resource::Processes => libc::setrlimit(libc::RLIMIT_NPROC, &rlim),
+                Resource::Core => libc::setrlimit(libc::RLIMIT_CORE, &rlim),
+                Resource::FileSize => libc::setrlimit(libc::RLIMIT_FSIZE, &rlim),
+            }
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// the limit a command was killed for, only cpu and fsize are reported by a
+// signal, other limits make syscalls fail in the command itself
+fn limit_exceeded(signal: Option<i32>) -> Option<&'static str> {
+    match signal {
+        Some(libc::SIGXCPU) => Some("cpu"),
+        Some(libc::SIGXFSZ) => Some("fsize"),
+        _ => None,
+    }
+}
+
+// how a timed out command is stopped: kill_signal first, then SIGKILL after kill_grace
+#[derive(Debug, Clone)]
+struct KillPolicy {
+    signal: signal::Signal,
+    grace: Duration,
+}
+
+impl Default for KillPolicy {
+    fn default() -> Self {
+        KillPolicy {
+            signal: signal::SIGTERM,
+            grace: Duration::from_secs(1),
+        }
+    }
 }
 
 impl Command {
-    fn new(name: &str, exec: &str, args: Vec<Regex>, time_limit: u64) -> Self {
+    fn new(name: &str, exec: &str, args: Vec<Argument>, time_limit: u64) -> Self {
         Command {
             name: name.to_string(),
             exec: exec.to_string(),
             args: args,
             time_limit: time_limit,
+            ..Default::default()
         }
     }
 
-    // get a command with arguments
-    fn get_command(self: &Self, arguments: Vec<String>) -> Result<(String, Vec<String>)> {
-        if arguments.len() != self.args.len() {
+    // lower the time limit for a single request
+    pub fn limit_time(self: &mut Self, time_limit: u64) {
+        if time_limit < self.time_limit {
+            self.time_limit = time_limit;
+        }
+    }
+
+    // wait for a free slot to run the command, held until it is dropped
+    pub async fn acquire(self: &Self) -> std::result::Result<Slot, Busy> {
+        let ret = self.limiter.acquire(&self.name).await;
+        if ret.is_err() {
+            COMMANDS.with_label_values(&["rejected", ""]).inc();
+        }
+        ret
+    }
+
+    // resolve values of all arguments, filling in defaults
+    fn get_arguments(
+        self: &Self,
+        arguments: Vec<String>,
+        mut named: HashMap<String, String>,
+    ) -> Result<(HashMap<String, String>, Vec<String>)> {
+        let positional: Vec<&Argument> = self.args.iter().filter(|a| a.index().is_some()).collect();
+        let (least, most) = match &self.variadic {
+            None => (
+                positional.iter().filter(|a| a.required).count(),
+                Some(positional.len()),
+            ),
+            Some(v) => (
+                positional.len() + v.min,
+                v.max.map(|m| positional.len() + m),
+            ),
+        };
+        if arguments.len() < least || most.map_or(false, |m| arguments.len() > m) {
             return Err(anyhow!(
                 "Illegal Argument: Got {} args ({} expected)",
                 arguments.len(),
-                self.args.len()
+                match most {
+                    Some(m) if m == least => least.to_string(),
+                    Some(m) => format!("{}-{}", least, m),
+                    None => format!("at least {}", least),
+                }
             ));
         }
-        for (i, arg) in arguments.iter().enumerate() {
-            // NOTE: allow empty argument
-            if arg == "" {
-                continue;
-            }
-            if !&self.args[i].is_match(arg) {
-                return Err(anyhow!("Illegal Argument: {}", arg));
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        let mut arguments = arguments.into_iter();
+        for arg in self.args.iter() {
+            let value = match arg.index() {
+                Some(_) => arguments.next(),
+                None => named.remove(&arg.name),
+            };
+            // NOTE: an optional argument without a default is left out
+            let value = match value {
+                Some(v) => v,
+                None => match &arg.default {
+                    Some(d) => d.clone(),
+                    None if arg.required => {
+                        return Err(anyhow!("Illegal Argument: {} is required", arg.name));
+                    }
+                    None => continue,
+                },
+            };
+            values.insert(arg.name.clone(), arg.check(value)?);
+        }
+        if let Some(name) = named.keys().next() {
+            return Err(anyhow!("Illegal Argument: unknown argument {}", name));
+        }
+        let mut rest: Vec<String> = Vec::new();
+        if let Some(v) = &self.variadic {
+            for value in arguments {
+                rest.push(v.arg.check(value)?);
             }
         }
+        Ok((values, rest))
+    }
+
+    // get a command with arguments
+    fn get_command(
+        self: &Self,
+        arguments: Vec<String>,
+        named: HashMap<String, String>,
+    ) -> Result<(String, Vec<String>)> {
+        let (values, rest) = self.get_arguments(arguments, named)?;
+        if self.shell {
+            return Ok(self.get_shell_command(values, rest));
+        }
 
         let mut cmd: &str = "";
         let mut args: Vec<String> = Vec::new();
@@ -78,177 +438,474 @@ impl Command {
         let splited = shlex::split(self.exec.as_str())
             .ok_or(0)
             .map_err(|_| anyhow!("Split command error for {}", self.name))?;
+        // the last argument is a flag of exec, left out with an absent value
+        let mut flag = false;
         for (i, arg) in splited.iter().enumerate() {
             // first argument is command
             if i == 0 {
                 cmd = arg;
                 continue;
             }
+            if arg == VARIADIC {
+                args.extend(rest.iter().cloned());
+                flag = false;
+                continue;
+            }
+            let mut absent = false;
             let a = re
-                .replace_all(arg, |caps: &Captures| match caps.get(1) {
-                    None => "".to_string(),
-                    Some(c) => match c.as_str().parse::<usize>() {
-                        Err(_) => {
-                            log::warn!("parse arg index error for {}: {}", self.name, arg,);
-                            "".to_string()
-                        }
-                        Ok(idx) => arguments[idx].clone(),
-                    },
+                .replace_all(arg, |caps: &Captures| match values.get(&caps[1]) {
+                    Some(v) => v.clone(),
+                    None => {
+                        absent |= self.args.iter().any(|a| a.name == caps[1]);
+                        caps[0].to_string()
+                    }
                 })
                 .into_owned();
-            args.push(a.trim_matches('"').trim_matches('\'').to_string());
+            if absent {
+                if flag && re.find(arg).map_or(false, |m| m.as_str() == arg) {
+                    args.pop();
+                }
+                flag = false;
+                continue;
+            }
+            flag = arg.starts_with('-') && !re.is_match(arg);
+            args.push(a);
         }
         Ok((cmd.to_string(), args))
     }
 
-    pub fn execute(self: &Self, arguments: Vec<String>) -> Result<CommandResult> {
-        let (cmd, args) = self.get_command(arguments)?;
+    // sh -c with placeholders replaced by shell variables, which are read from
+    // the positional parameters so values never become part of the script
+    fn get_shell_command(
+        self: &Self,
+        mut values: HashMap<String, String>,
+        rest: Vec<String>,
+    ) -> (String, Vec<String>) {
+        let mut script = String::new();
+        let mut params: Vec<String> = Vec::new();
+        for (i, arg) in self.args.iter().enumerate() {
+            script.push_str(&format!("{}{}=\"${{{}}}\"\n", SHELL_VAR, arg.name, i + 1));
+            params.push(values.remove(&arg.name).unwrap_or_default());
+        }
+        if !self.args.is_empty() {
+            script.push_str(&format!("shift {}\n", self.args.len()));
+        }
+        // NOTE: a placeholder already quoted must stay quoted once replaced
+        let re = Regex::new(RE_SHELL_ARGS).unwrap();
+        let mut quote = Quote::None;
+        let mut last = 0;
+        for caps in re.captures_iter(&self.exec) {
+            let m = caps.get(0).unwrap();
+            let before = &self.exec[last..m.start()];
+            quote = quote.after(before);
+            script.push_str(before);
+            last = m.end();
+            let var = match &caps[1] {
+                "@" => "@".to_string(),
+                name if self.args.iter().any(|a| a.name == name) => format!("{}{}", SHELL_VAR, name),
+                _ => {
+                    script.push_str(m.as_str());
+                    continue;
+                }
+            };
+            script.push_str(&match quote {
+                Quote::None => format!("\"${}\"", var),
+                Quote::Double => format!("${}", var),
+                Quote::Single => format!("'\"${}\"'", var),
+            });
+        }
+        script.push_str(&self.exec[last..]);
+
+        let mut args = vec!["-c".to_string(), script, self.name.clone()];
+        args.extend(params);
+        args.extend(rest);
+        ("/bin/sh".to_string(), args)
+    }
+
+    // why a command can not run on this host
+    fn unsupported(self: &Self) -> Option<String> {
+        match &self.landlock {
+            Some(l) if !l.supported() => {
+                Some("Landlock Error: not supported by the kernel".to_string())
+            }
+            _ => None,
+        }
+    }
 
-        let start = SystemTime::now();
+    fn write_script(self: &Self, args: &mut Vec<String>) -> Result<Option<ScriptFile>> {
+        let script = match &self.script {
+            None => return Ok(None),
+            Some(s) => s,
+        };
+        let file = ScriptFile::create(&self.name, &script.body, self.credential.as_ref())?;
+        let path = file
+            .path
+            .to_str()
+            .ok_or(anyhow!("Script path error for {}", self.name))?;
+        args.insert(script.position, path.to_string());
+        Ok(Some(file))
+    }
 
-        let mut command = process::Command::new(&cmd);
+    fn build_command(
+        self: &Self,
+        cmd: &str,
+        args: Vec<String>,
+        cgroup: Option<&Cgroup>,
+        script: Option<&ScriptFile>,
+    ) -> Result<(process::Command, Option<Arc<Setup>>)> {
+        let mut command = process::Command::new(cmd);
         command.args(args);
+        self.env.apply(&mut command);
+        let credential = self.credential.clone();
+        let limits = self.limits.clone();
+        let procs = cgroup.map(|c| c.procs.clone());
+        let sandbox = self.sandbox.clone();
+        let kill_signal = self.kill.signal as libc::c_int;
+        let setup = match &sandbox {
+            Some(_) => Some(Arc::new(Setup::new()?)),
+            None => None,
+        };
+        let progress = setup.clone();
+        let hardening = self.hardening.clone();
+        let landlock = self.landlock.clone();
+        // NOTE: only scripts under /tmp are hidden by the private one of the sandbox
+        let script = match script {
+            Some(s) if s.path.starts_with("/tmp") => Some(CString::new(s.path.as_os_str().as_bytes())?),
+            _ => None,
+        };
         unsafe {
-            command.pre_exec(|| setsid().map_err(err_nix2io).map(|_| ()));
+            command.pre_exec(move || {
+                setsid().map_err(err_nix2io)?;
+                // NOTE: before switching user, which may not write cgroup.procs
+                if let Some(procs) = &procs {
+                    cgroup::enter(procs)?;
+                }
+                // NOTE: mounts need root, so before switching user
+                if let (Some(sandbox), Some(progress)) = (&sandbox, &progress) {
+                    sandbox.enter(script.as_deref(), progress, kill_signal)?;
+                }
+                for limit in limits.iter() {
+                    limit.apply()?;
+                }
+                if let Some(hardening) = &hardening {
+                    hardening.drop_caps()?;
+                }
+                if let Some(credential) = &credential {
+                    credential.switch().map_err(err_nix2io)?;
+                }
+                if let Some(landlock) = &landlock {
+                    landlock.restrict()?;
+                }
+                if let Some(hardening) = &hardening {
+                    hardening.restrict()?;
+                }
+                Ok(())
+            });
         }
-
-        let mut child = command
+        command
             .stdout(process::Stdio::piped())
-            .stderr(process::Stdio::piped())
-            .spawn()?;
+            .stderr(process::Stdio::piped());
+        Ok((command, setup))
+    }
 
+    pub async fn execute(
+        self: &Self,
+        arguments: Vec<String>,
+        named: HashMap<String, String>,
+    ) -> Result<CommandResult> {
+        let (cmd, mut args) = self.get_command(arguments, named)?;
+        if let Some(err) = self.unsupported() {
+            return Ok(CommandResult::err(err));
+        }
+        // NOTE: removed when dropped at the end of the run
+        let _script = self.write_script(&mut args)?;
+
+        let cgroup = Cgroup::create(&self.name)?;
+
+        let start = SystemTime::now();
+
+        let (command, setup) = self.build_command(&cmd, args, cgroup.as_ref(), _script.as_ref())?;
+        // NOTE: the cgroup already holds every descendant
+        let mut descendants = match cgroup {
+            None => Some(Descendants::new()),
+            Some(_) => None,
+        };
+        let (mut child, _guard) = match reaper::spawn(command, descendants.as_mut()) {
+            Ok(c) => c,
+            // NOTE: spawn fails with the error of the first failing sandbox step
+            Err(e) if setup.as_ref().map_or(false, |s| s.failed()) => {
+                return Ok(CommandResult::err(format!("Sandbox Error: {}", e)));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let pid = Pid::from_raw(child.id().ok_or(anyhow!("spawn error"))? as i32);
+        let mut run = RunGuard::new(&self.name, pid, cgroup.as_ref(), descendants);
+
+        let mut stdout_pipe = child.stdout.take().ok_or(anyhow!("stdout error"))?;
+        let mut stderr_pipe = child.stderr.take().ok_or(anyhow!("stderr error"))?;
+        let mut stdout = PipeOutput::new(self.max_output_bytes);
+        let mut stderr = PipeOutput::new(self.max_output_bytes);
+
+        // NOTE: pipes are drained while waiting, or the child would block on a full pipe
         let timeout = Duration::from_secs(self.time_limit);
-        let status = child.wait_timeout(timeout)?;
-
-        match status {
-            None => kill_child(&mut child),
-            Some(s) => {
-                let stdout = match child.stdout.as_mut() {
-                    None => "".to_string(),
-                    Some(out) => {
-                        let mut ss = String::new();
-                        out.read_to_string(&mut ss)?;
-                        ss
-                    }
-                };
-                let stderr = match child.stderr.as_mut() {
-                    None => "".to_string(),
-                    Some(err) => {
-                        let mut ss = String::new();
-                        err.read_to_string(&mut ss)?;
-                        ss
-                    }
-                };
+        let status = time::timeout(timeout, async {
+            let running = async {
+                tokio::try_join!(
+                    child.wait(),
+                    stdout.read_from(&mut stdout_pipe),
+                    stderr.read_from(&mut stderr_pipe)
+                )
+            };
+            watch_descendants(running, run.descendants.as_ref()).await
+        })
+        .await;
+
+        let ret: Result<CommandResult> = match status {
+            Err(_) => {
+                COMMANDS.with_label_values(&["timeout", ""]).inc();
+                run.counted = true;
+                let (stopped, sig) = kill_child(&mut child, pid, &self.kill, cgroup.as_ref(), run.descendants.as_ref()).await?;
+                run.finished = true;
+                // collect what is left in the pipes after the kill
+                let _ = time::timeout(Duration::from_millis(100), async {
+                    tokio::try_join!(
+                        stdout.read_from(&mut stdout_pipe),
+                        stderr.read_from(&mut stderr_pipe)
+                    )
+                })
+                .await;
+                let truncated = stdout.truncated || stderr.truncated;
+                let mut ret = CommandResult::timeout(
+                    format!("Time Limit Exceeded: {}", stopped),
+                    Some(stdout.into_string_lossy()),
+                    Some(stderr.into_string_lossy()),
+                    sig.to_string(),
+                    start.elapsed()?.as_secs_f64(),
+                    start.duration_since(UNIX_EPOCH)?.as_secs_f64(),
+                );
+                ret.set_truncated(truncated);
+                Ok(ret)
+            }
+            Ok(status) => {
+                run.finished = true;
+                run.counted = true;
+                let (s, _, _) = status?;
+                let truncated = stdout.truncated || stderr.truncated;
+                let (stdout, stderr) = (stdout.into_string()?, stderr.into_string()?);
                 match s.code() {
-                    None => {
-                        COMMANDS.with_label_values(&["terminated", ""]).inc();
-                        Ok(CommandResult::err("Terminated by signal".to_string()))
+                    None => match limit_exceeded(s.signal()) {
+                        Some(limit) => {
+                            COMMANDS.with_label_values(&["limit", ""]).inc();
+                            let mut ret = CommandResult::limit_exceeded(
+                                limit.to_string(),
+                                Some(stdout),
+                                Some(stderr),
+                                signal_name(s.signal()),
+                                start.elapsed()?.as_secs_f64(),
+                                start.duration_since(UNIX_EPOCH)?.as_secs_f64(),
+                            );
+                            ret.set_truncated(truncated);
+                            Ok(ret)
+                        }
+                        None => {
+                            COMMANDS.with_label_values(&["terminated", ""]).inc();
+                            Ok(CommandResult::err("Terminated by signal".to_string()))
+                        }
                     },
                     Some(code) => {
                         COMMANDS.with_label_values(&["ok", &code.to_string()]).inc();
-                        Ok(CommandResult::ok(
+                        let mut ret = CommandResult::ok(
                             stdout,
                             stderr,
                             code,
                             start.elapsed()?.as_secs_f64(),
                             start.duration_since(UNIX_EPOCH)?.as_secs_f64(),
-                        ))
+                        );
+                        ret.set_truncated(truncated);
+                        Ok(ret)
                     },
                 }
             }
+        };
+        let mut ret = ret?;
+        if let Some(cgroup) = &cgroup {
+            let (memory_peak, cpu_time) = cgroup.usage();
+            ret.set_usage(memory_peak, cpu_time);
         }
+        Ok(ret)
     }
 
-    pub fn execute_iter(
+    pub async fn execute_iter(
         self: &Self,
         arguments: Vec<String>,
-        tx: std::sync::mpsc::Sender<String>,
-        waker: &mut Arc<Mutex<RedarrowWaker>>,
+        named: HashMap<String, String>,
+        tx: &mpsc::Sender<String>,
     ) -> Result<CommandResult> {
-        let (cmd, args) = self.get_command(arguments)?;
+        let (cmd, mut args) = self.get_command(arguments, named)?;
+        if let Some(err) = self.unsupported() {
+            return Ok(CommandResult::err(err));
+        }
+        // NOTE: removed when dropped at the end of the run
+        let _script = self.write_script(&mut args)?;
+
+        let cgroup = Cgroup::create(&self.name)?;
 
         let start = SystemTime::now();
 
-        let mut command = process::Command::new(&cmd);
-        command.args(args);
-        unsafe {
-            command.pre_exec(|| setsid().map_err(err_nix2io).map(|_| ()));
-        }
+        let (command, setup) = self.build_command(&cmd, args, cgroup.as_ref(), _script.as_ref())?;
+        // NOTE: the cgroup already holds every descendant
+        let mut descendants = match cgroup {
+            None => Some(Descendants::new()),
+            Some(_) => None,
+        };
+        let (mut child, _guard) = match reaper::spawn(command, descendants.as_mut()) {
+            Ok(c) => c,
+            // NOTE: spawn fails with the error of the first failing sandbox step
+            Err(e) if setup.as_ref().map_or(false, |s| s.failed()) => {
+                return Ok(CommandResult::err(format!("Sandbox Error: {}", e)));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let pid = Pid::from_raw(child.id().ok_or(anyhow!("spawn error"))? as i32);
+        let mut run = RunGuard::new(&self.name, pid, cgroup.as_ref(), descendants);
+
+        let mut stdout_pipe = child.stdout.take().ok_or(anyhow!("stdout error"))?;
+        let mut stderr_pipe = child.stderr.take().ok_or(anyhow!("stderr error"))?;
+        let mut stdout = PipeSender::new(1, self.max_output_bytes);
+        let mut stderr = PipeSender::new(2, self.max_output_bytes);
 
-        let mut child = command
-            .stdout(process::Stdio::piped())
-            .stderr(process::Stdio::piped())
-            .spawn()?;
-
-        let stdout_reader = BufReader::new(child.stdout.take().ok_or(anyhow!("stdout error"))?);
-        let out_tx = tx.clone();
-        let out_waker = waker.clone();
-        let stdout_child = thread::Builder::new()
-            .name(format!("stdout sender: {}", &cmd))
-            .spawn(move || {
-                stdout_reader
-                    .lines()
-                    .filter_map(|line| line.ok())
-                    .for_each(|line| match out_tx.send(format!("1> {}\n", line)) {
-                        Err(_) => log::warn!("error sending to stdout: {}", line),
-                        Ok(()) => {
-                            if let Ok(mut waker) = out_waker.lock() {
-                                waker.wake();
-                            } else {
-                                log::warn!("waker on stdout failed to get lock");
-                            }
-                        }
-                    });
-            })?;
-        let stderr_reader = BufReader::new(child.stderr.take().ok_or(anyhow!("stderr error"))?);
-        let err_tx = tx.clone();
-        let err_waker = waker.clone();
-        let stderr_child = thread::Builder::new()
-            .name(format!("stderr sender: {}", &cmd))
-            .spawn(move || {
-                stderr_reader
-                    .lines()
-                    .filter_map(|line| line.ok())
-                    .for_each(|line| match err_tx.send(format!("2> {}\n", line)) {
-                        Err(_) => log::warn!("error sending to stderr: {}", line),
-                        Ok(()) => {
-                            if let Ok(mut waker) = err_waker.lock() {
-                                waker.wake();
-                            } else {
-                                log::warn!("waker on stderr failed to get lock");
-                            }
-                        }
-                    });
-            })?;
         let timeout = Duration::from_secs(self.time_limit);
-        let status = child.wait_timeout(timeout)?;
-
-        match status {
-            // FIXME:(everpcpc) stdout_child and stderr_child should be force terminated
-            None => kill_child(&mut child),
-            Some(s) => {
-                stdout_child
-                    .join()
-                    .map_err(|e| anyhow!("stdout failed: {:?}", e))?;
-                stderr_child
-                    .join()
-                    .map_err(|e| anyhow!("stderr failed: {:?}", e))?;
+        let streaming = time::timeout(timeout, async {
+            let running = async {
+                tokio::try_join!(
+                    child.wait(),
+                    stdout.send_from(&mut stdout_pipe, tx),
+                    stderr.send_from(&mut stderr_pipe, tx)
+                )
+            };
+            watch_descendants(running, run.descendants.as_ref()).await
+        });
+        // NOTE: tx is closed once the response is dropped by the client
+        let status = tokio::select! {
+            status = streaming => Some(status),
+            _ = tx.closed() => None,
+        };
+
+        let ret: Result<CommandResult> = match status {
+            None => {
+                COMMANDS.with_label_values(&["cancelled", ""]).inc();
+                run.counted = true;
+                let (stopped, _) = kill_child(&mut child, pid, &self.kill, cgroup.as_ref(), run.descendants.as_ref()).await?;
+                run.finished = true;
+                log::info!("command {} cancelled by client: {}", self.name, stopped);
+                Ok(CommandResult::err(format!("Cancelled: {}", stopped)))
+            }
+            Some(Err(_)) => {
+                COMMANDS.with_label_values(&["timeout", ""]).inc();
+                run.counted = true;
+                let (stopped, sig) = kill_child(&mut child, pid, &self.kill, cgroup.as_ref(), run.descendants.as_ref()).await?;
+                run.finished = true;
+                // NOTE: close our ends of the pipes, descendants still holding
+                // them must not keep the response open
+                drop(stdout_pipe);
+                drop(stderr_pipe);
+                let mut ret = CommandResult::timeout(
+                    format!("Time Limit Exceeded: {}", stopped),
+                    None,
+                    None,
+                    sig.to_string(),
+                    start.elapsed()?.as_secs_f64(),
+                    start.duration_since(UNIX_EPOCH)?.as_secs_f64(),
+                );
+                ret.set_truncated(stdout.truncated || stderr.truncated);
+                Ok(ret)
+            }
+            Some(Ok(status)) => {
+                run.finished = true;
+                run.counted = true;
+                let (s, _, _) = status?;
                 match s.code() {
-                    None => {
-                        COMMANDS.with_label_values(&["terminated", ""]).inc();
-                        Ok(CommandResult::err("Terminated by signal".to_string()))
+                    None => match limit_exceeded(s.signal()) {
+                        Some(limit) => {
+                            COMMANDS.with_label_values(&["limit", ""]).inc();
+                            let mut ret = CommandResult::limit_exceeded(
+                                limit.to_string(),
+                                None,
+                                None,
+                                signal_name(s.signal()),
+                                start.elapsed()?.as_secs_f64(),
+                                start.duration_since(UNIX_EPOCH)?.as_secs_f64(),
+                            );
+                            ret.set_truncated(stdout.truncated || stderr.truncated);
+                            Ok(ret)
+                        }
+                        None => {
+                            COMMANDS.with_label_values(&["terminated", ""]).inc();
+                            Ok(CommandResult::err("Terminated by signal".to_string()))
+                        }
                     },
                     Some(code) => {
                         COMMANDS.with_label_values(&["ok", &code.to_string()]).inc();
-                        Ok(CommandResult::chunked_ok(
+                        let mut ret = CommandResult::chunked_ok(
                             code,
                             start.elapsed()?.as_secs_f64(),
                             start.duration_since(UNIX_EPOCH)?.as_secs_f64(),
-                        ))
+                        );
+                        ret.set_truncated(stdout.truncated || stderr.truncated);
+                        Ok(ret)
                     },
                 }
             }
+        };
+        let mut ret = ret?;
+        if let Some(cgroup) = &cgroup {
+            let (memory_peak, cpu_time) = cgroup.usage();
+            ret.set_usage(memory_peak, cpu_time);
+        }
+        Ok(ret)
+    }
+}
+
+// quotes open at some point of a shell command
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Quote {
+    None,
+    Single,
+    Double,
+}
+
+impl Quote {
+    // quotes open after the text
+    fn after(self: Self, text: &str) -> Self {
+        let mut quote = self;
+        let mut escaped = false;
+        for c in text.chars() {
+            if escaped {
+                escaped = false;
+                continue;
+            }
+            quote = match (quote, c) {
+                (Quote::Single, '\'') => Quote::None,
+                (Quote::Single, _) => Quote::Single,
+                (_, '\\') => {
+                    escaped = true;
+                    quote
+                }
+                (Quote::None, '\'') => Quote::Single,
+                (Quote::None, '"') => Quote::Double,
+                (Quote::Double, '"') => Quote::None,
+                _ => quote,
+            };
         }
+        quote
+    }
+}
+
+fn signal_name(signal: Option<i32>) -> String {
+    match signal.map(signal::Signal::try_from) {
+        Some(Ok(sig)) => sig.to_string(),
+        Some(Err(_)) => signal.unwrap_or_default().to_string(),
+        None => "".to_string(),
     }
 }
 
@@ -260,24 +917,295 @@ fn err_nix2io(err: nix::Error) -> std::io::Error {
     }
 }
 
-fn kill_child(child: &mut process::Child) -> Result<CommandResult> {
-    COMMANDS.with_label_values(&["timeout", ""]).inc();
-    let pid = Pid::from_raw(child.id() as i32);
-    signal::killpg(pid, signal::SIGTERM).map_err(|e| anyhow!("Kill failed: {}", e))?;
-    let one_sec = Duration::from_secs(1);
-    Ok(match child.wait_timeout(one_sec)? {
-        Some(s) => CommandResult::err(format!("Time Limit Exceeded: {}", s)),
-        None => {
-            signal::killpg(pid, signal::SIGKILL)
-                .map_err(|e| anyhow!("Force kill failed: {}", e))?;
-            CommandResult::err("Time Limit Exceeded: killed".to_string())
+// kill the process group, returns how the child stopped and the last signal sent
+async fn kill_child(
+    child: &mut tokio::process::Child,
+    pid: Pid,
+    kill: &KillPolicy,
+    cgroup: Option<&Cgroup>,
+    descendants: Option<&Descendants>,
+) -> Result<(String, signal::Signal)> {
+    let ret = kill_group(child, pid, kill).await;
+    if let Some(cgroup) = cgroup {
+        if let Err(e) = cgroup.kill() {
+            log::warn!("kill cgroup of {} failed: {}", pid, e);
         }
-    })
+    }
+    // NOTE: daemons which left the session are only found this way
+    if let Some(descendants) = descendants {
+        descendants.kill().await;
+    }
+    ret
+}
+
+async fn kill_group(
+    child: &mut tokio::process::Child,
+    pid: Pid,
+    kill: &KillPolicy,
+) -> Result<(String, signal::Signal)> {
+    // NOTE: ESRCH means the whole group is gone, while descendants which left
+    // it may still hold the pipes
+    killpg(pid, kill.signal).map_err(|e| anyhow!("Kill failed: {}", e))?;
+    let (stopped, sig) = match time::timeout(kill.grace, child.wait()).await {
+        Ok(s) => (s?.to_string(), kill.signal),
+        Err(_) => {
+            killpg(pid, signal::SIGKILL).map_err(|e| anyhow!("Force kill failed: {}", e))?;
+            child.wait().await?;
+            ("killed".to_string(), signal::SIGKILL)
+        }
+    };
+    if sig != signal::SIGKILL {
+        // NOTE: the rest of the group may outlive the leader
+        if let Err(e) = killpg(pid, signal::SIGKILL) {
+            log::warn!("kill rest of process group {} failed: {}", pid, e);
+        }
+    }
+    Ok((stopped, sig))
+}
+
+// signal a process group, which is fine to be gone already
+fn killpg(pid: Pid, sig: signal::Signal) -> nix::Result<()> {
+    match signal::killpg(pid, sig) {
+        Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => Ok(()),
+        r => r,
+    }
+}
+
+// kills what is left of a run whose future is dropped before it finished,
+// like a non-chunked request of a client that went away
+struct RunGuard<'a> {
+    name: String,
+    pid: Pid,
+    cgroup: Option<&'a Cgroup>,
+    descendants: Option<Descendants>,
+    // the child exited or was killed
+    finished: bool,
+    // the outcome is in COMMANDS, before killing which may fail or be dropped
+    counted: bool,
+}
+
+impl<'a> RunGuard<'a> {
+    fn new(name: &str, pid: Pid, cgroup: Option<&'a Cgroup>, descendants: Option<Descendants>) -> Self {
+        RunGuard {
+            name: name.to_string(),
+            pid: pid,
+            cgroup: cgroup,
+            descendants: descendants,
+            finished: false,
+            counted: false,
+        }
+    }
+}
+
+impl Drop for RunGuard<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if !self.counted {
+            COMMANDS.with_label_values(&["cancelled", ""]).inc();
+            log::info!("command {} cancelled by client: killed", self.name);
+        }
+        // NOTE: the child is not reaped yet, so the group id is still ours
+        let _ = signal::killpg(self.pid, signal::SIGKILL);
+        if let Some(cgroup) = self.cgroup {
+            if let Err(e) = cgroup.kill() {
+                log::warn!("kill cgroup of {} failed: {}", self.pid, e);
+            }
+        }
+        if let Some(descendants) = self.descendants.take() {
+            // NOTE: the /proc walk must not block the runtime
+            match tokio::runtime::Handle::try_current() {
+                Ok(rt) => drop(rt.spawn_blocking(move || descendants.kill_blocking())),
+                Err(_) => descendants.kill_blocking(),
+            }
+        }
+    }
+}
+
+// run a command, tracking its descendants meanwhile
+async fn watch_descendants<F: std::future::Future>(
+    running: F,
+    descendants: Option<&Descendants>,
+) -> F::Output {
+    match descendants {
+        None => running.await,
+        Some(descendants) => tokio::select! {
+            r = running => r,
+            _ = descendants.watch() => unreachable!(),
+        },
+    }
+}
+
+// a private copy of an inline script for a single run
+struct ScriptFile {
+    path: PathBuf,
+}
+
+impl ScriptFile {
+    // owned by the user running it, which has to read it
+    fn create(name: &str, body: &str, owner: Option<&Credential>) -> Result<Self> {
+        let seq = SCRIPT_SEQ.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("redarrow-{}-{}-{}", name, process::id(), seq));
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        let script = ScriptFile { path: path };
+        if let Some(owner) = owner {
+            chown(&script.path, owner.uid, Some(owner.gid))?;
+        }
+        file.write_all(body.as_bytes())?;
+        file.write_all(b"\n")?;
+        Ok(script)
+    }
+}
+
+impl Drop for ScriptFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("remove script {} error: {}", self.path.display(), e);
+        }
+    }
+}
+
+// output captured from a pipe, keeping at most limit bytes
+struct PipeOutput {
+    output: Vec<u8>,
+    limit: Option<usize>,
+    truncated: bool,
+}
+
+impl PipeOutput {
+    fn new(limit: Option<usize>) -> Self {
+        PipeOutput {
+            output: Vec::new(),
+            limit: limit,
+            truncated: false,
+        }
+    }
+
+    // read the pipe until EOF, output read so far is kept if cancelled
+    async fn read_from<R: AsyncRead + Unpin>(self: &mut Self, pipe: &mut R) -> std::io::Result<()> {
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = pipe.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            match self.limit {
+                // NOTE: keep draining after the limit, or the child blocks on a full pipe
+                Some(limit) if self.output.len() + n > limit => {
+                    let left = limit.saturating_sub(self.output.len());
+                    self.output.extend_from_slice(&buf[..left]);
+                    self.truncated = true;
+                }
+                _ => self.output.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    fn into_string(mut self: Self) -> std::io::Result<String> {
+        if self.truncated {
+            // drop the last character if it was cut in the middle
+            if let Err(e) = std::str::from_utf8(&self.output) {
+                if e.error_len().is_none() {
+                    let valid = e.valid_up_to();
+                    self.output.truncate(valid);
+                }
+            }
+        }
+        String::from_utf8(self.output)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    // partial output may be cut anywhere
+    fn into_string_lossy(self: Self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+// forwards lines of a pipe to the chunked response, prefixed with the fd
+struct PipeSender {
+    fd: u8,
+    sent: usize,
+    limit: Option<usize>,
+    truncated: bool,
+}
+
+impl PipeSender {
+    fn new(fd: u8, limit: Option<usize>) -> Self {
+        PipeSender {
+            fd: fd,
+            sent: 0,
+            limit: limit,
+            truncated: false,
+        }
+    }
+
+    async fn send_from<R: AsyncRead + Unpin>(
+        self: &mut Self,
+        pipe: &mut R,
+        tx: &mpsc::Sender<String>,
+    ) -> std::io::Result<()> {
+        let mut reader = tokio::io::BufReader::new(pipe);
+        let mut buf: Vec<u8> = Vec::new();
+        loop {
+            buf.clear();
+            if read_line(&mut reader, &mut buf, MAX_LINE).await? == 0 {
+                return Ok(());
+            }
+            self.sent += buf.len();
+            if let Some(limit) = self.limit {
+                if self.sent > limit {
+                    self.truncated = true;
+                    continue;
+                }
+            }
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches('\n').trim_end_matches('\r');
+            // NOTE: waits for the client when the channel is full, which
+            // stops reading the pipe and eventually blocks the command
+            if tx.send(format!("{}> {}\n", self.fd, line)).await.is_err() {
+                // receiver dropped, the command is being cancelled
+                return Ok(());
+            }
+        }
+    }
+}
+
+// like read_until a newline, but stops after max bytes so output without
+// newlines is never buffered whole
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    max: usize,
+) -> std::io::Result<usize> {
+    let mut read = 0;
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(read);
+        }
+        let available = &available[..available.len().min(max - read)];
+        let (n, done) = match available.iter().position(|b| *b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (available.len(), read + available.len() == max),
+        };
+        buf.extend_from_slice(&available[..n]);
+        reader.consume(n);
+        read += n;
+        if done {
+            return Ok(read);
+        }
+    }
 }
 
 pub fn read_config(config_file: &str) -> Result<Configs> {
     let p = Path::new(config_file);
     let mut cmds: Configs = HashMap::new();
+    let mut unanchored: Vec<String> = Vec::new();
 
     if p.is_dir() {
         let d = p.join("*");
@@ -286,15 +1214,26 @@ pub fn read_config(config_file: &str) -> Result<Configs> {
             .ok_or(0)
             .map_err(|_| anyhow!("Config dir error"))?;
         for e in glob(dir)? {
-            parse_config_file(e?, &mut cmds)?;
+            parse_config_file(e?, &mut cmds, &mut unanchored)?;
         }
     } else {
-        parse_config_file(p, &mut cmds)?;
+        parse_config_file(p, &mut cmds, &mut unanchored)?;
+    }
+    if !unanchored.is_empty() {
+        unanchored.sort();
+        log::warn!(
+            "commands with unanchored argument patterns: {}",
+            unanchored.join(", ")
+        );
     }
     Ok(cmds)
 }
 
-fn parse_config_file<P: AsRef<Path>>(config_file: P, cmds: &mut Configs) -> Result<()> {
+fn parse_config_file<P: AsRef<Path>>(
+    config_file: P,
+    cmds: &mut Configs,
+    unanchored: &mut Vec<String>,
+) -> Result<()> {
     let conf = Ini::load_from_file_noescape(config_file)?;
 
     'outer: for (sec, prop) in conf.iter() {
@@ -303,68 +1242,321 @@ fn parse_config_file<P: AsRef<Path>>(config_file: P, cmds: &mut Configs) -> Resu
             Some(n) => n,
         };
 
-        let exec = match prop.get("exec") {
-            None => continue,
-            Some(e) => e,
+        // NOTE: for an inline script, exec only holds the arguments passed
+        // to it after the interpreter and the script file
+        let script = prop.get("script");
+        let exec = match (prop.get("exec"), script) {
+            (None, None) => continue,
+            (Some(e), None) => e.to_string(),
+            (e, Some(_)) => format!(
+                "{} {}",
+                prop.get("interpreter").map_or("/bin/sh", |i| i.as_str()),
+                e.map_or("", |e| e.as_str())
+            ),
+        };
+        // NOTE: exec is only run by a shell when explicitly asked for,
+        // arguments are passed to it as positional parameters
+        let shell = match prop.get("shell") {
+            Some(v) => parse_bool(v)?,
+            None => false,
         };
-        // NOTE:(everpcpc) shell pipe not supported
-        if exec.contains("|") {
-            log::warn!("ignored command with pipe: {}", name);
+        if exec.contains("|") && !shell {
+            log::warn!("ignored command with pipe: {}, set shell = true to allow", name);
             continue;
         }
 
-        let mut args: Vec<Regex> = Vec::new();
-        for cap in Regex::new(RE_ARGS)?.captures_iter(exec) {
-            let arg_name = format!("arg{}", cap.get(1).map_or("0", |m| m.as_str()));
-            let arg = prop
-                .get(arg_name.as_str())
-                .ok_or(0)
-                .map_err(|_| anyhow!("{} not found for {}", arg_name, name))?;
+        // NOTE: argument patterns must match the whole argument unless
+        // full_match is turned off for legacy configs
+        let full_match = match prop.get("full_match") {
+            Some(v) => parse_bool(v)?,
+            None => true,
+        };
+        if !full_match {
+            log::warn!("command {} matches arguments partially", name);
+        }
 
-            let arg_re = match Regex::new(arg) {
-                Ok(r) => r,
+        let mut args: Vec<Argument> = Vec::new();
+        for cap in Regex::new(RE_ARGS)?.captures_iter(&exec) {
+            let arg_name = cap.get(1).map_or("0", |m| m.as_str());
+            if args.iter().any(|a| a.name == arg_name) {
+                continue;
+            }
+            let key = Argument::key(arg_name);
+            if !prop.contains_key(&key) && !prop.contains_key(&format!("{}.type", key)) {
+                // NOTE: undeclared names are left as they are, like ${HOME}
+                // read by a shell
+                if arg_name.parse::<usize>().is_err() {
+                    continue;
+                }
+                log::error!("ignored error command {}: {} not found", name, key);
+                continue 'outer;
+            }
+            match parse_argument(arg_name, prop, full_match) {
+                Ok(arg) => args.push(arg),
                 Err(e) => {
                     log::error!("ignored error command {}: {}", name, e);
                     continue 'outer;
                 }
-            };
-            args.push(arg_re);
+            }
+            if prop.get(&key).map_or(false, |p| !is_anchored(p)) && !unanchored.iter().any(|n| n == name) {
+                unanchored.push(name.to_string());
+            }
+        }
+        if let Err(e) = check_positional(&mut args) {
+            log::error!("ignored error command {}: {}", name, e);
+            continue;
+        }
+
+        let variadic = if exec.contains(VARIADIC) {
+            match parse_variadic(&exec, prop, full_match, shell, &args) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    log::error!("ignored error command {}: {}", name, e);
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        if prop.get("args").map_or(false, |p| !is_anchored(p)) && !unanchored.iter().any(|n| n == name) {
+            unanchored.push(name.to_string());
         }
 
         let time_limit: u64 = match prop.get("time_limit") {
             Some(limit) => limit.parse()?,
             None => 30,
         };
-        let cmd = Command::new(name, exec, args, time_limit);
+        let script = match script {
+            None => None,
+            Some(_) if shell => {
+                log::error!("ignored error command {}: script can not be run by shell", name);
+                continue;
+            }
+            Some(body) => {
+                let interpreter = prop.get("interpreter").map_or("/bin/sh", |i| i.as_str());
+                match shlex::split(interpreter) {
+                    Some(tokens) if !tokens.is_empty() => Some(Script {
+                        body: body.to_string(),
+                        position: tokens.len() - 1,
+                    }),
+                    _ => {
+                        log::error!("ignored error command {}: invalid interpreter", name);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let env = match Environment::parse(prop) {
+            Ok(env) => env,
+            Err(e) => {
+                log::error!("ignored error command {}: {}", name, e);
+                continue;
+            }
+        };
+
+        let credential = match Credential::parse(prop) {
+            Ok(credential) => credential,
+            Err(e) => {
+                log::error!("ignored error command {}: {}", name, e);
+                continue;
+            }
+        };
+
+        let limits = match ResourceLimit::parse(prop) {
+            Ok(limits) => limits,
+            Err(e) => {
+                log::error!("ignored error command {}: {}", name, e);
+                continue;
+            }
+        };
+
+        let sandbox = match Sandbox::parse(prop) {
+            Ok(sandbox) => sandbox,
+            Err(e) => {
+                log::error!("ignored error command {}: {}", name, e);
+                continue;
+            }
+        };
+
+        let hardening = match Hardening::parse(prop) {
+            Ok(hardening) => hardening,
+            Err(e) => {
+                log::error!("ignored error command {}: {}", name, e);
+                continue;
+            }
+        };
+
+        let landlock = match Landlock::parse(name, prop) {
+            Ok(landlock) => landlock,
+            Err(e) => {
+                log::error!("ignored error command {}: {}", name, e);
+                continue;
+            }
+        };
+
+        let limiter = match Limiter::parse(prop) {
+            Ok(limiter) => limiter,
+            Err(e) => {
+                log::error!("ignored error command {}: {}", name, e);
+                continue;
+            }
+        };
+
+        let mut cmd = Command::new(name, &exec, args, time_limit);
+        cmd.variadic = variadic;
+        cmd.shell = shell;
+        cmd.script = script;
+        cmd.env = env;
+        cmd.credential = credential;
+        cmd.limits = limits;
+        cmd.sandbox = sandbox;
+        cmd.hardening = hardening;
+        cmd.landlock = landlock;
+        cmd.limiter = limiter;
+        if let Some(limit) = prop.get("max_output_bytes") {
+            match limit.parse() {
+                Ok(limit) => cmd.max_output_bytes = Some(limit),
+                Err(e) => {
+                    log::error!("ignored error command {}: max_output_bytes {}", name, e);
+                    continue;
+                }
+            }
+        }
+        if let Some(sig) = prop.get("kill_signal") {
+            match parse_signal(sig) {
+                Ok(sig) => cmd.kill.signal = sig,
+                Err(e) => {
+                    log::error!("ignored error command {}: {}", name, e);
+                    continue;
+                }
+            }
+        }
+        if let Some(grace) = prop.get("kill_grace") {
+            match grace.parse() {
+                Ok(grace) => cmd.kill.grace = Duration::from_secs(grace),
+                Err(e) => {
+                    log::error!("ignored error command {}: kill_grace {}", name, e);
+                    continue;
+                }
+            }
+        }
 
         cmds.insert(name.to_string(), cmd);
     }
     Ok(())
 }
 
-#[derive(Debug)]
-pub struct RedarrowWaker {
-    waker: Option<Waker>,
+// run each command in its own child group of root for accounting and killing
+pub fn set_cgroup_root(root: &str) -> Result<()> {
+    cgroup::set_root(Path::new(root))
 }
 
-impl RedarrowWaker {
-    pub fn new() -> Self {
-        RedarrowWaker { waker: None }
+// apply what takes effect on every run of a command, once configs are served
+pub fn apply_configs(configs: &Configs) {
+    for cmd in configs.values() {
+        cmd.limiter.apply(&cmd.name);
     }
-    pub fn register(&mut self, waker: &Waker) {
-        match self.waker {
-            None => self.waker = Some(waker.clone()),
-            Some(_) => {}
+}
+
+// limit the runs of all commands at the same time
+pub fn set_max_concurrency(max: usize) -> Result<()> {
+    concurrency::set_global(max)
+}
+
+// adopt and reap orphaned descendants of commands, must be called within
+// the runtime
+pub fn set_subreaper() -> Result<()> {
+    reaper::set_subreaper()
+}
+
+// parse the pattern, type and default of an argument
+fn parse_argument(
+    arg_name: &str,
+    prop: &HashMap<String, String>,
+    full_match: bool,
+) -> Result<Argument> {
+    let key = Argument::key(arg_name);
+    let rule = Rule::parse(&key, prop)?;
+    let pattern = prop
+        .get(key.as_str())
+        .map(|p| arg_regex(p, full_match))
+        .transpose()?;
+    let mut arg = Argument::new(arg_name, pattern, rule);
+    arg.default = prop.get(format!("{}.default", key).as_str()).map(|d| d.to_string());
+    arg.required = match prop.get(format!("{}.required", key).as_str()) {
+        Some(r) => parse_bool(r)?,
+        None => arg.default.is_none(),
+    };
+    Ok(arg)
+}
+
+// ${@} takes all remaining positional arguments, declared by the args keys
+fn parse_variadic(
+    exec: &str,
+    prop: &HashMap<String, String>,
+    full_match: bool,
+    shell: bool,
+    args: &Vec<Argument>,
+) -> Result<Variadic> {
+    if !shell {
+        let tokens = shlex::split(exec).ok_or(anyhow!("split exec error"))?;
+        if tokens.iter().filter(|t| t.contains(VARIADIC)).any(|t| t != VARIADIC) {
+            return Err(anyhow!("{} must be a whole argument", VARIADIC));
         }
     }
-    pub fn wake(&mut self) -> bool {
-        if let Some(waker) = self.waker.take() {
-            waker.wake();
-            true
-        } else {
-            false
-        }
+    if args.iter().any(|a| a.index().is_some() && !a.required) {
+        return Err(anyhow!("{} can not follow optional arguments", VARIADIC));
+    }
+    if !prop.contains_key("args") && !prop.contains_key("args.type") {
+        return Err(anyhow!("args not found"));
     }
+    let arg = parse_argument("@", prop, full_match)?;
+    let min = match prop.get("args.min_count") {
+        Some(m) => m.parse()?,
+        None => 0,
+    };
+    let max = match prop.get("args.max_count") {
+        Some(m) => Some(m.parse()?),
+        None => None,
+    };
+    Ok(Variadic {
+        arg: arg,
+        min: min,
+        max: max,
+    })
+}
+
+// bytes with an optional binary unit: 4096, 512K, 64M, 1G
+fn parse_size(value: &str) -> Result<u64> {
+    let (num, scale) = match value.chars().last() {
+        Some('K') | Some('k') => (&value[..value.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    let num: u64 = num.parse().map_err(|_| anyhow!("Invalid size: {}", value))?;
+    num.checked_mul(scale).ok_or(anyhow!("Invalid size: {}", value))
+}
+
+pub(crate) fn parse_bool(value: &str) -> Result<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(anyhow!("Invalid boolean: {}", value)),
+    }
+}
+
+// accepts both SIGINT and INT
+fn parse_signal(name: &str) -> Result<signal::Signal> {
+    let name = name.trim().to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    name.parse()
+        .map_err(|_| anyhow!("Unknown signal: {}", name))
 }
 
 #[cfg(test)]
@@ -376,10 +1568,11 @@ mod tests {
         let (cmd, args) = Command {
             name: "test".to_string(),
             exec: "sleep ${0}".to_string(),
-            args: vec![Regex::new(r"[A-Za-z0-9._~:/?@!$&'()*+,=-]+").unwrap()],
+            args: vec![Argument::new("0", Some(Regex::new(r"[A-Za-z0-9._~:/?@!$&'()*+,=-]+").unwrap()), None)],
             time_limit: 5,
+            ..Default::default()
         }
-        .get_command(vec!["1".to_string()])
+        .get_command(vec!["1".to_string()], HashMap::new())
         .unwrap();
         assert_eq!(cmd, "sleep");
         assert_eq!(args, vec!["1"]);
@@ -390,10 +1583,14 @@ mod tests {
         let (cmd, args) = Command {
             name: "test".to_string(),
             exec: "echo ${0} \"${1}\"".to_string(),
-            args: vec![Regex::new(r"\d+").unwrap(), Regex::new(r"[\d ]+").unwrap()],
+            args: vec![
+                Argument::new("0", Some(Regex::new(r"\d+").unwrap()), None),
+                Argument::new("1", Some(Regex::new(r"[\d ]+").unwrap()), None),
+            ],
             time_limit: 5,
+            ..Default::default()
         }
-        .get_command(vec!["1".to_string(), "3 4".to_string()])
+        .get_command(vec!["1".to_string(), "3 4".to_string()], HashMap::new())
         .unwrap();
         assert_eq!(cmd, "echo");
         assert_eq!(args, vec!["1".to_string(), "3 4".to_string()]);
@@ -401,10 +1598,14 @@ mod tests {
         let (cmd, args) = Command {
             name: "test".to_string(),
             exec: "echo \'${0}\' \'${1}\'".to_string(),
-            args: vec![Regex::new(r"\w+").unwrap(), Regex::new(r"[\w ]+").unwrap()],
+            args: vec![
+                Argument::new("0", Some(Regex::new(r"\w+").unwrap()), None),
+                Argument::new("1", Some(Regex::new(r"[\w ]+").unwrap()), None),
+            ],
             time_limit: 5,
+            ..Default::default()
         }
-        .get_command(vec!["1".to_string(), "34".to_string()])
+        .get_command(vec!["1".to_string(), "34".to_string()], HashMap::new())
         .unwrap();
         assert_eq!(cmd, "echo");
         assert_eq!(args, vec!["1", "34"]);
@@ -416,15 +1617,319 @@ mod tests {
             name: "test".to_string(),
             exec: "echo -e \"${0} ${1}\" ${2}".to_string(),
             args: vec![
-                Regex::new(r"\w+").unwrap(),
-                Regex::new(r"[\w ]+").unwrap(),
-                Regex::new(r"[\w ]+").unwrap(),
+                Argument::new("0", Some(Regex::new(r"\w+").unwrap()), None),
+                Argument::new("1", Some(Regex::new(r"[\w ]+").unwrap()), None),
+                Argument::new("2", Some(Regex::new(r"[\w ]+").unwrap()), None),
             ],
             time_limit: 5,
+            ..Default::default()
         }
-        .get_command(vec!["1".to_string(), "4".to_string(), "8".to_string()])
+        .get_command(vec!["1".to_string(), "4".to_string(), "8".to_string()], HashMap::new())
         .unwrap();
         assert_eq!(cmd, "echo");
         assert_eq!(args, vec!["-e", "1 4", "8"]);
     }
+
+    #[test]
+    fn test_get_command_with_named() {
+        let mut service = Argument::new("service", Some(Regex::new(r"^\w+$").unwrap()), None);
+        service.default = Some("nginx".to_string());
+        service.required = false;
+        let mut lines = Argument::new("1", Some(Regex::new(r"^\d+$").unwrap()), None);
+        lines.required = false;
+        let command = Command {
+            name: "test".to_string(),
+            exec: "journalctl -u ${service} ${0} -n ${1} ${HOME}".to_string(),
+            args: vec![
+                Argument::new("0", Some(Regex::new(r"^-\w$").unwrap()), None),
+                lines,
+                service,
+            ],
+            time_limit: 5,
+            ..Default::default()
+        };
+
+        let (cmd, args) = command
+            .get_command(vec!["-f".to_string()], HashMap::new())
+            .unwrap();
+        assert_eq!(cmd, "journalctl");
+        assert_eq!(args, vec!["-u", "nginx", "-f", "${HOME}"]);
+
+        let mut named = HashMap::new();
+        named.insert("service".to_string(), "redis".to_string());
+        let (_, args) = command
+            .get_command(vec!["-f".to_string(), "10".to_string()], named)
+            .unwrap();
+        assert_eq!(args, vec!["-u", "redis", "-f", "-n", "10", "${HOME}"]);
+
+        assert!(command.get_command(vec![], HashMap::new()).is_err());
+        let mut named = HashMap::new();
+        named.insert("unknown".to_string(), "x".to_string());
+        assert!(command.get_command(vec!["-f".to_string()], named).is_err());
+    }
+
+    #[test]
+    fn test_get_command_with_variadic() {
+        let command = Command {
+            name: "test".to_string(),
+            exec: "wc ${0} ${@}".to_string(),
+            args: vec![Argument::new("0", Some(Regex::new(r"^-\w$").unwrap()), None)],
+            variadic: Some(Variadic {
+                arg: Argument::new("@", Some(Regex::new(r"^[\w.]+$").unwrap()), None),
+                min: 1,
+                max: Some(3),
+            }),
+            time_limit: 5,
+            ..Default::default()
+        };
+
+        let files = |n: usize| -> Vec<String> {
+            let mut args = vec!["-l".to_string()];
+            args.extend((0..n).map(|i| format!("{}.log", i)));
+            args
+        };
+        let (cmd, args) = command.get_command(files(2), HashMap::new()).unwrap();
+        assert_eq!(cmd, "wc");
+        assert_eq!(args, vec!["-l", "0.log", "1.log"]);
+
+        let err = command.get_command(files(0), HashMap::new()).unwrap_err();
+        assert_eq!(err.to_string(), "Illegal Argument: Got 1 args (2-4 expected)");
+        assert!(command.get_command(files(4), HashMap::new()).is_err());
+        let bad = vec!["-l".to_string(), "a.log".to_string(), "; ls".to_string()];
+        assert!(command.get_command(bad, HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_get_shell_command() {
+        let command = Command {
+            name: "grep".to_string(),
+            exec: "ps -ef | grep ${0} | head -n ${lines} ${@} ${HOME}".to_string(),
+            args: vec![
+                Argument::new("0", None, None),
+                Argument::new("lines", None, None),
+            ],
+            variadic: Some(Variadic {
+                arg: Argument::new("@", None, None),
+                min: 0,
+                max: None,
+            }),
+            shell: true,
+            time_limit: 5,
+            ..Default::default()
+        };
+
+        let mut named = HashMap::new();
+        named.insert("lines".to_string(), "3".to_string());
+        let (cmd, args) = command
+            .get_command(vec!["$(id); x".to_string(), "-q".to_string()], named)
+            .unwrap();
+        assert_eq!(cmd, "/bin/sh");
+        assert_eq!(
+            args,
+            vec![
+                "-c",
+                "REDARROW_ARG_0=\"${1}\"\nREDARROW_ARG_lines=\"${2}\"\nshift 2\n\
+                 ps -ef | grep \"$REDARROW_ARG_0\" | head -n \"$REDARROW_ARG_lines\" \"$@\" ${HOME}",
+                "grep",
+                "$(id); x",
+                "3",
+                "-q",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_shell_command_quoted() {
+        let command = Command {
+            name: "quoted".to_string(),
+            exec: r#"printf '%s|' "${0}" '${0}' ${0} "x ${0} \"${0}\"" 'y"${0}' "${HOME}""#.to_string(),
+            args: vec![Argument::new("0", None, None)],
+            shell: true,
+            time_limit: 5,
+            ..Default::default()
+        };
+
+        let (cmd, args) = command.get_command(vec!["a  *".to_string()], HashMap::new()).unwrap();
+        assert_eq!(
+            args[1],
+            "REDARROW_ARG_0=\"${1}\"\nshift 1\n\
+             printf '%s|' \"$REDARROW_ARG_0\" ''\"$REDARROW_ARG_0\"'' \"$REDARROW_ARG_0\" \
+             \"x $REDARROW_ARG_0 \\\"$REDARROW_ARG_0\\\"\" 'y\"'\"$REDARROW_ARG_0\"'' \"${HOME}\""
+        );
+        let output = process::Command::new(cmd)
+            .args(args)
+            .env("HOME", "/h")
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "a  *|a  *|a  *|x a  * \"a  *\"|y\"a  *|/h|"
+        );
+    }
+
+    #[test]
+    fn test_write_script() {
+        let command = Command {
+            name: "test".to_string(),
+            exec: "/bin/bash -e ${0}".to_string(),
+            args: vec![Argument::new("0", None, None)],
+            script: Some(Script {
+                body: "echo $1".to_string(),
+                position: 1,
+            }),
+            time_limit: 5,
+            ..Default::default()
+        };
+
+        let (cmd, mut args) = command
+            .get_command(vec!["hi".to_string()], HashMap::new())
+            .unwrap();
+        let script = command.write_script(&mut args).unwrap().unwrap();
+        assert_eq!(cmd, "/bin/bash");
+        assert_eq!(args, vec!["-e", script.path.to_str().unwrap(), "hi"]);
+        assert_eq!(fs::read_to_string(&script.path).unwrap(), "echo $1\n");
+        let path = script.path.clone();
+        drop(script);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_environment() {
+        let mut prop: HashMap<String, String> = HashMap::new();
+        prop.insert("cwd".to_string(), "/".to_string());
+        prop.insert("env_clear".to_string(), "true".to_string());
+        prop.insert("env_passthrough".to_string(), "HOME, NOT_SET_FOR_REDARROW".to_string());
+        prop.insert("env.LANG".to_string(), "C".to_string());
+        let env = Environment::parse(&prop).unwrap();
+        assert_eq!(env.path.as_deref(), Some(DEFAULT_PATH));
+
+        let mut command = process::Command::new("env");
+        env.apply(&mut command);
+        assert_eq!(command.get_current_dir(), Some(Path::new("/")));
+        let mut vars: Vec<_> = command
+            .get_envs()
+            .map(|(k, _)| k.to_str().unwrap())
+            .collect();
+        vars.sort();
+        let mut expected = vec!["LANG", "PATH"];
+        if env::var_os("HOME").is_some() {
+            expected.insert(0, "HOME");
+        }
+        assert_eq!(vars, expected);
+
+        prop.insert("cwd".to_string(), "/nonexistent/redarrow".to_string());
+        assert!(Environment::parse(&prop).is_err());
+    }
+
+    #[test]
+    fn test_credential() {
+        let mut prop: HashMap<String, String> = HashMap::new();
+        assert!(Credential::parse(&prop).unwrap().is_none());
+
+        prop.insert("user".to_string(), "root".to_string());
+        prop.insert("groups".to_string(), "0".to_string());
+        let credential = Credential::parse(&prop).unwrap().unwrap();
+        assert_eq!(credential.uid, Some(Uid::from_raw(0)));
+        assert_eq!(credential.gid, Gid::from_raw(0));
+        assert_eq!(credential.groups, vec![Gid::from_raw(0)]);
+
+        prop.insert("user".to_string(), "no-such-user-for-redarrow".to_string());
+        assert!(Credential::parse(&prop).is_err());
+        prop.remove("user");
+        assert!(Credential::parse(&prop).is_err());
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let mut prop: HashMap<String, String> = HashMap::new();
+        prop.insert("rlimit.as".to_string(), "512M".to_string());
+        prop.insert("rlimit.cpu".to_string(), "10".to_string());
+        let limits = ResourceLimit::parse(&prop).unwrap();
+        assert_eq!(limits.len(), 2);
+        assert_eq!(limits[0].resource, Resource::AddressSpace);
+        assert_eq!(limits[0].value, 512 << 20);
+        assert_eq!(limits[1].resource, Resource::Cpu);
+        assert_eq!(limits[1].value, 10);
+
+        prop.insert("rlimit.nofile".to_string(), "1M".to_string());
+        assert!(ResourceLimit::parse(&prop).is_err());
+        prop.remove("rlimit.nofile");
+        prop.insert("rlimit.as".to_string(), "17179869184G".to_string());
+        assert!(ResourceLimit::parse(&prop).is_err());
+        assert_eq!(limit_exceeded(Some(libc::SIGXCPU)), Some("cpu"));
+        assert_eq!(limit_exceeded(Some(libc::SIGKILL)), None);
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGINT").unwrap(), signal::SIGINT);
+        assert_eq!(parse_signal("quit").unwrap(), signal::SIGQUIT);
+        assert!(parse_signal("SIGNOPE").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_failed() {
+        let dir = env::temp_dir().join(format!("redarrow-reload-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let conf = |max: usize| format!("[reload]\nexec = true\nmax_concurrency = {}\n", max);
+        fs::write(dir.join("a.conf"), conf(1)).unwrap();
+        let configs = read_config(dir.to_str().unwrap()).unwrap();
+        apply_configs(&configs);
+        let command = &configs["reload"];
+        let _slot = command.acquire().await.unwrap();
+
+        // a later file fails the whole reload
+        fs::write(dir.join("a.conf"), conf(2)).unwrap();
+        fs::write(dir.join("b.conf"), "[broken\n").unwrap();
+        assert!(read_config(dir.to_str().unwrap()).is_err());
+        assert!(matches!(command.acquire().await, Err(Busy::Command(1))));
+
+        // a section skipped after its max_concurrency was read
+        fs::write(dir.join("a.conf"), conf(2) + "max_output_bytes = 1k\n").unwrap();
+        fs::remove_file(dir.join("b.conf")).unwrap();
+        let skipped = read_config(dir.to_str().unwrap()).unwrap();
+        assert!(skipped.is_empty());
+        apply_configs(&skipped);
+        assert!(matches!(command.acquire().await, Err(Busy::Command(1))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_killpg_gone() {
+        let mut child = process::Command::new("/bin/true").spawn().unwrap();
+        child.wait().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+        assert!(killpg(pid, signal::SIGKILL).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pipe_output_truncated() {
+        let mut output = PipeOutput::new(None);
+        output.read_from(&mut "abc".as_bytes()).await.unwrap();
+        assert!(!output.truncated);
+        assert_eq!(output.into_string().unwrap(), "abc");
+
+        let mut output = PipeOutput::new(Some(4));
+        output.read_from(&mut "中文abc".as_bytes()).await.unwrap();
+        assert!(output.truncated);
+        assert_eq!(output.into_string().unwrap(), "中");
+    }
+
+    #[tokio::test]
+    async fn test_pipe_sender_long_line() {
+        let (tx, mut rx) = mpsc::channel::<String>(16);
+        let output = format!("{}\nok\n", "x".repeat(MAX_LINE * 2 + 1));
+        let mut sender = PipeSender::new(1, None);
+        sender.send_from(&mut output.as_bytes(), &tx).await.unwrap();
+        drop(tx);
+        let mut lines = Vec::new();
+        while let Some(line) = rx.recv().await {
+            lines.push(line);
+        }
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0].len(), MAX_LINE + 4);
+        assert_eq!(lines[2], "1> x\n");
+        assert_eq!(lines[3], "1> ok\n");
+    }
 }
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use anyhow::{anyhow, Result};
+
+use crate::dispatcher::parse_bool;
+
+// capabilities by their number, as in linux/capability.h
+static CAPABILITIES: &[&str] = &[
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "kill",
+    "setgid",
+    "setuid",
+    "setpcap",
+    "linux_immutable",
+    "net_bind_service",
+    "net_broadcast",
+    "net_admin",
+    "net_raw",
+    "ipc_lock",
+    "ipc_owner",
+    "sys_module",
+    "sys_rawio",
+    "sys_chroot",
+    "sys_ptrace",
+    "sys_pacct",
+    "sys_admin",
+    "sys_boot",
+    "sys_nice",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "mknod",
+    "lease",
+    "audit_write",
+    "audit_control",
+    "setfcap",
+    "mac_override",
+    "mac_admin",
+    "syslog",
+    "wake_alarm",
+    "block_suspend",
+    "audit_read",
+    "perfmon",
+    "bpf",
+    "checkpoint_restore",
+];
+
+// restrictions of a command, installed in pre_exec
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Hardening {
+    no_new_privs: bool,
+    drop_caps: Vec<libc::c_ulong>,
+    seccomp: Vec<Vec<Insn>>,
+}
+
+impl Hardening {
+    pub(crate) fn parse(prop: &HashMap<String, String>) -> Result<Option<Self>> {
+        let mut h = Hardening::default();
+        if let Some(v) = prop.get("no_new_privs") {
+            h.no_new_privs = parse_bool(v)?;
+        }
+        if let Some(caps) = prop.get("drop_caps") {
+            for cap in caps.split(",").map(|c| c.trim()).filter(|c| *c != "") {
+                if cap == "all" {
+                    h.drop_caps.extend(0..=last_cap());
+                    continue;
+                }
+                let name = cap.to_lowercase();
+                let name = name.strip_prefix("cap_").unwrap_or(&name);
+                match CAPABILITIES.iter().position(|c| *c == name) {
+                    Some(n) => h.drop_caps.push(n as libc::c_ulong),
+                    None => return Err(anyhow!("unknown capability {}", cap)),
+                }
+            }
+            // NOTE: capabilities unknown to the running kernel can not be dropped
+            let last = last_cap();
+            h.drop_caps.retain(|c| *c <= last);
+        }
+        if let Some(profiles) = prop.get("seccomp") {
+            for profile in profiles.split(",").map(|p| p.trim()).filter(|p| *p != "") {
+                h.seccomp.push(seccomp_profile(profile)?);
+            }
+            // NOTE: required to install a filter without CAP_SYS_ADMIN
+            h.no_new_privs = true;
+        }
+        if !h.no_new_privs && h.drop_caps.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(h))
+    }
+
+    // needs CAP_SETPCAP, so before switching user
+    pub(crate) fn drop_caps(self: &Self) -> io::Result<()> {
+        for cap in self.drop_caps.iter() {
+            if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, *cap, 0, 0, 0) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    // the last step before exec
+    pub(crate) fn restrict(self: &Self) -> io::Result<()> {
+        if self.no_new_privs && unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for filter in self.seccomp.iter() {
+            let prog = libc::sock_fprog {
+                len: filter.len() as libc::c_ushort,
+                filter: filter.as_ptr() as *mut libc::sock_filter,
+            };
+            let ret = unsafe {
+                libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    libc::SECCOMP_MODE_FILTER,
+                    &prog as *const libc::sock_fprog,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn last_cap() -> libc::c_ulong {
+    fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|c| c.trim().parse().ok())
+        .unwrap_or(CAPABILITIES.len() as libc::c_ulong - 1)
+}
+
+// same layout as libc::sock_filter, which lacks Debug
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Insn {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+const LD_ABS: u16 = 0x20;
+const JEQ: u16 = 0x15;
+const JGE: u16 = 0x35;
+const JSET: u16 = 0x45;
+const RET: u16 = 0x06;
+
+const RET_ALLOW: u32 = 0x7fff_0000;
+const RET_ERRNO: u32 = 0x0005_0000;
+const RET_KILL_PROCESS: u32 = 0x8000_0000;
+
+// offsets in struct seccomp_data
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+fn data_arg(n: u32) -> u32 {
+    16 + 8 * n
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+fn insn(code: u16, k: u32, jt: u8, jf: u8) -> Insn {
+    Insn {
+        code: code,
+        jt: jt,
+        jf: jf,
+        k: k,
+    }
+}
+
+enum Rule {
+    Deny(libc::c_long, i32),
+    // deny when any of mask is set in the argument
+    DenyFlags(libc::c_long, u32, u32, i32),
+    // deny unless the argument equals the value
+    DenyUnless(libc::c_long, u32, u32, i32),
+}
+
+fn seccomp_profile(name: &str) -> Result<Vec<Insn>> {
+    let rules = match name {
+        "no-network" => vec![Rule::DenyUnless(
+            libc::SYS_socket,
+            0,
+            libc::AF_UNIX as u32,
+            libc::EACCES,
+        )],
+        "read-only" => read_only_rules(),
+        _ => return Err(anyhow!("unknown seccomp profile {}", name)),
+    };
+    Ok(assemble(&rules))
+}
+
+// linux 6.6, not in libc yet, the same number on every arch
+const SYS_FCHMODAT2: libc::c_long = 452;
+
+fn read_only_rules() -> Vec<Rule> {
+    let write = (libc::O_WRONLY | libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC) as u32;
+    let mut rules = vec![
+        Rule::DenyFlags(libc::SYS_openat, 2, write, libc::EROFS),
+        // NOTE: flags of openat2 are behind a pointer
+        Rule::Deny(libc::SYS_openat2, libc::ENOSYS),
+        // NOTE: io_uring operations never pass the filter, callers fall back
+        // to plain syscalls
+        Rule::Deny(libc::SYS_io_uring_setup, libc::ENOSYS),
+        Rule::Deny(libc::SYS_io_uring_enter, libc::ENOSYS),
+        Rule::Deny(libc::SYS_io_uring_register, libc::ENOSYS),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    {
+        rules.push(Rule::DenyFlags(libc::SYS_open, 1, write, libc::EROFS));
+        for nr in [
+            libc::SYS_creat,
+            libc::SYS_unlink,
+            libc::SYS_rename,
+            libc::SYS_mkdir,
+            libc::SYS_rmdir,
+            libc::SYS_link,
+            libc::SYS_symlink,
+            libc::SYS_chmod,
+            libc::SYS_chown,
+            libc::SYS_lchown,
+            libc::SYS_mknod,
+            libc::SYS_utime,
+            libc::SYS_utimes,
+        ]
+        .iter()
+        {
+            rules.push(Rule::Deny(*nr, libc::EROFS));
+        }
+    }
+    for nr in [
+        libc::SYS_unlinkat,
+        libc::SYS_renameat,
+        libc::SYS_renameat2,
+        libc::SYS_mkdirat,
+        libc::SYS_linkat,
+        libc::SYS_symlinkat,
+        libc::SYS_truncate,
+        libc::SYS_fchmod,
+        libc::SYS_fchmodat,
+        SYS_FCHMODAT2,
+        libc::SYS_fchown,
+        libc::SYS_fchownat,
+        libc::SYS_mknodat,
+        libc::SYS_utimensat,
+        libc::SYS_setxattr,
+        libc::SYS_lsetxattr,
+        libc::SYS_fsetxattr,
+        libc::SYS_removexattr,
+        libc::SYS_lremovexattr,
+        libc::SYS_fremovexattr,
+    ]
+    .iter()
+    {
+        rules.push(Rule::Deny(*nr, libc::EROFS));
+    }
+    rules
+}
+
+// a filter checking the arch, then each rule in turn, allowing the rest
+fn assemble(rules: &[Rule]) -> Vec<Insn> {
+    let mut prog = vec![
+        insn(LD_ABS, DATA_ARCH, 0, 0),
+        insn(JEQ, AUDIT_ARCH, 1, 0),
+        insn(RET, RET_KILL_PROCESS, 0, 0),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    {
+        // NOTE: x32 syscalls share the arch but not the numbers
+        prog.push(insn(LD_ABS, DATA_NR, 0, 0));
+        prog.push(insn(JGE, 0x4000_0000, 0, 1));
+        prog.push(insn(RET, RET_ERRNO | libc::ENOSYS as u32, 0, 0));
+    }
+    for rule in rules {
+        let (nr, body) = match rule {
+            Rule::Deny(nr, errno) => (nr, vec![insn(RET, RET_ERRNO | *errno as u32, 0, 0)]),
+            Rule::DenyFlags(nr, arg, mask, errno) => (
+                nr,
+                vec![
+                    insn(LD_ABS, data_arg(*arg), 0, 0),
+                    insn(JSET, *mask, 0, 1),
+                    insn(RET, RET_ERRNO | *errno as u32, 0, 0),
+                    insn(RET, RET_ALLOW, 0, 0),
+                ],
+            ),
+            Rule::DenyUnless(nr, arg, value, errno) => (
+                nr,
+                vec![
+                    insn(LD_ABS, data_arg(*arg), 0, 0),
+                    insn(JEQ, *value, 1, 0),
+                    insn(RET, RET_ERRNO | *errno as u32, 0, 0),
+                    insn(RET, RET_ALLOW, 0, 0),
+                ],
+            ),
+        };
+        prog.push(insn(LD_ABS, DATA_NR, 0, 0));
+        prog.push(insn(JEQ, *nr as u32, 0, body.len() as u8));
+        prog.extend(body);
+    }
+    prog.push(insn(RET, RET_ALLOW, 0, 0));
+    prog
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    fn run(profile: &str, script: &str) -> std::process::Output {
+        let mut prop: HashMap<String, String> = HashMap::new();
+        prop.insert("seccomp".to_string(), profile.to_string());
+        let h = Hardening::parse(&prop).unwrap().unwrap();
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(script);
+        unsafe {
+            command.pre_exec(move || h.restrict());
+        }
+        command.output().unwrap()
+    }
+
+    // errno of a syscall made under the profile
+    fn errno(profile: &str, nr: libc::c_long, args: [libc::c_long; 4]) -> Option<i32> {
+        let mut prop: HashMap<String, String> = HashMap::new();
+        prop.insert("seccomp".to_string(), profile.to_string());
+        let h = Hardening::parse(&prop).unwrap().unwrap();
+        let mut command = Command::new("/bin/true");
+        unsafe {
+            command.pre_exec(move || {
+                h.restrict()?;
+                match libc::syscall(nr, args[0], args[1], args[2], args[3]) {
+                    -1 => Err(io::Error::last_os_error()),
+                    _ => Ok(()),
+                }
+            });
+        }
+        command.output().err()?.raw_os_error()
+    }
+
+    #[test]
+    fn test_seccomp_profiles() {
+        let path = std::env::temp_dir().join(format!("redarrow-seccomp-{}", std::process::id()));
+        let script = format!("head -n 1 /etc/passwd && echo x > {}", path.display());
+        let out = run("read-only", &script);
+        assert!(!out.stdout.is_empty());
+        assert!(!out.status.success());
+        assert!(!path.exists());
+
+        let mut params = [0u8; 120];
+        let setup = [8, params.as_mut_ptr() as libc::c_long, 0, 0];
+        assert_eq!(errno("read-only", libc::SYS_io_uring_setup, setup), Some(libc::ENOSYS));
+        assert_eq!(errno("read-only", libc::SYS_io_uring_enter, [0; 4]), Some(libc::ENOSYS));
+        assert_eq!(errno("read-only", libc::SYS_io_uring_register, [0; 4]), Some(libc::ENOSYS));
+        let missing = b"/nonexistent\0".as_ptr() as libc::c_long;
+        let chmod = [libc::AT_FDCWD as libc::c_long, missing, 0o777, 0];
+        assert_eq!(errno("read-only", SYS_FCHMODAT2, chmod), Some(libc::EROFS));
+        assert_eq!(errno("no-network", SYS_FCHMODAT2, chmod), Some(libc::ENOENT));
+
+        let out = run("no-network", "echo ok");
+        assert_eq!(out.stdout, b"ok\n");
+
+        let mut prop: HashMap<String, String> = HashMap::new();
+        prop.insert("seccomp".to_string(), "no-such-profile".to_string());
+        assert!(Hardening::parse(&prop).is_err());
+        prop.remove("seccomp");
+        prop.insert("drop_caps".to_string(), "CAP_NET_RAW, sys_admin".to_string());
+        assert_eq!(Hardening::parse(&prop).unwrap().unwrap().drop_caps, vec![13, 21]);
+    }
+}
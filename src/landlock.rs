@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+
+use crate::dispatcher::parse_bool;
+
+const CREATE_RULESET_VERSION: u32 = 1;
+const RULE_PATH_BENEATH: u32 = 1;
+
+const ACCESS_EXECUTE: u64 = 1 << 0;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+const ACCESS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_READ: u64 = ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR;
+// the only rights a rule on a file, not a directory, may have
+const ACCESS_FILE: u64 = ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE | ACCESS_TRUNCATE;
+
+lazy_static! {
+    // landlock ABI of the running kernel, 0 when not supported
+    static ref ABI: i64 = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    }
+    .max(0);
+}
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+// directories a command may read or write, applied before exec
+#[derive(Debug, Clone)]
+pub(crate) struct Landlock {
+    handled: u64,
+    rules: Vec<(CString, u64)>,
+}
+
+impl Landlock {
+    pub(crate) fn parse(name: &str, prop: &HashMap<String, String>) -> Result<Option<Self>> {
+        if !prop.contains_key("landlock.read") && !prop.contains_key("landlock.write") {
+            return Ok(None);
+        }
+        // refuse to run on kernels without landlock instead of skipping it
+        let strict = match prop.get("landlock.strict") {
+            Some(v) => parse_bool(v)?,
+            None => false,
+        };
+        // everything known to the kernel is denied unless allowed by a rule
+        let handled = match *ABI {
+            0 => 0,
+            1 => (1 << 13) - 1,
+            2 => (1 << 14) - 1,
+            3 | 4 => (1 << 15) - 1,
+            _ => (1 << 16) - 1,
+        };
+        if handled == 0 && !strict {
+            log::warn!("landlock not supported by the kernel, skipped for {}", name);
+            return Ok(None);
+        }
+
+        let mut rules = Vec::new();
+        for (key, access) in [("landlock.read", ACCESS_READ), ("landlock.write", handled)].iter() {
+            let paths = match prop.get(*key) {
+                None => continue,
+                Some(p) => p,
+            };
+            for path in paths.split(",").map(|p| p.trim()).filter(|p| *p != "") {
+                let p = Path::new(path);
+                if !p.is_absolute() || !p.exists() {
+                    return Err(anyhow!("{} {} not found", key, path));
+                }
+                let access = if p.is_dir() { *access } else { *access & ACCESS_FILE };
+                rules.push((CString::new(path)?, access));
+            }
+        }
+        Ok(Some(Landlock {
+            handled: handled,
+            rules: rules,
+        }))
+    }
+
+    pub(crate) fn supported(self: &Self) -> bool {
+        self.handled != 0
+    }
+
+    // needs no_new_privs unless the process has CAP_SYS_ADMIN
+    pub(crate) fn restrict(self: &Self) -> io::Result<()> {
+        unsafe {
+            let attr = RulesetAttr {
+                handled_access_fs: self.handled,
+            };
+            let ruleset = libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            );
+            if ruleset < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ruleset = ruleset as libc::c_int;
+            let ret = self.add_rules(ruleset).and_then(|_| {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                    || libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+            libc::close(ruleset);
+            ret
+        }
+    }
+
+    unsafe fn add_rules(self: &Self, ruleset: libc::c_int) -> io::Result<()> {
+        for (path, access) in self.rules.iter() {
+            let fd = libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let attr = PathBeneathAttr {
+                allowed_access: *access,
+                parent_fd: fd,
+            };
+            let ret = libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset,
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            );
+            let err = io::Error::last_os_error();
+            libc::close(fd);
+            if ret != 0 {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    #[test]
+    fn test_landlock() {
+        let mut prop: HashMap<String, String> = HashMap::new();
+        prop.insert("landlock.read".to_string(), "/usr, /lib, /lib64, /bin, /proc".to_string());
+        if *ABI == 0 {
+            // skipped unless strict, which keeps the command to refuse runs
+            assert!(Landlock::parse("test", &prop).unwrap().is_none());
+            prop.insert("landlock.strict".to_string(), "true".to_string());
+            assert!(!Landlock::parse("test", &prop).unwrap().unwrap().supported());
+            return;
+        }
+        let landlock = Landlock::parse("test", &prop).unwrap().unwrap();
+        assert!(landlock.supported());
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg("ls /usr > /dev/null; cat /proc/self/status > /dev/null; head -c 1 /etc/passwd");
+        unsafe {
+            command.pre_exec(move || landlock.restrict());
+        }
+        let out = command.output().unwrap();
+        assert!(out.stdout.is_empty());
+        assert!(!out.status.success());
+
+        prop.insert("landlock.write".to_string(), "/nonexistent/redarrow".to_string());
+        assert!(Landlock::parse("test", &prop).is_err());
+    }
+}
@@ -1,7 +1,16 @@
+mod argument;
+mod cgroup;
+mod concurrency;
 pub mod dispatcher;
+mod hardening;
+mod landlock;
+mod reaper;
+mod sandbox;
 pub mod webclient;
 
 use prometheus::{TextEncoder, Encoder, Opts, Counter, Registry, Gauge};
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -9,6 +18,75 @@ pub struct CommandParams {
     pub chunked: Option<u8>,
     pub argument: Option<String>,
     pub format: Option<String>,
+    // NOTE: sent as repeated `arg` and `arg.<name>` query params, which
+    // urlencoded structs can not express, so they are handled by with_query
+    // and to_query
+    #[serde(skip)]
+    pub arg: Vec<String>,
+    #[serde(skip)]
+    pub named: HashMap<String, String>,
+}
+
+impl CommandParams {
+    // collect repeated `arg` and `arg.<name>` params from the query pairs
+    pub fn with_query(mut self: Self, query: Vec<(String, String)>) -> Self {
+        for (k, v) in query {
+            if k == "arg" {
+                self.arg.push(v);
+            } else if k.starts_with("arg.") {
+                self.named.insert(k["arg.".len()..].to_string(), v);
+            }
+        }
+        self
+    }
+
+    pub fn to_query(self: &Self) -> Vec<(String, &str)> {
+        let mut query: Vec<(String, &str)> =
+            self.arg.iter().map(|a| ("arg".to_string(), a.as_str())).collect();
+        for (k, v) in self.named.iter() {
+            query.push((format!("arg.{}", k), v.as_str()));
+        }
+        query
+    }
+
+    pub fn into_request(self: Self, command: String) -> CommandRequest {
+        CommandRequest {
+            command: command,
+            arguments: self.arguments(),
+            named_arguments: self.named,
+            format: self.format,
+            chunked: self.chunked.map_or(false, |c| c != 0),
+            timeout: None,
+        }
+    }
+
+    // arguments from `arg` are kept verbatim, the legacy `argument` is split by space
+    pub fn arguments(self: &Self) -> Vec<String> {
+        if !self.arg.is_empty() {
+            return self.arg.clone();
+        }
+        match &self.argument {
+            None => Vec::new(),
+            Some(a) => a.split(" ").map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+// body of POST /command, keeps arguments out of urls and access logs
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommandRequest {
+    pub command: String,
+    #[serde(default)]
+    pub arguments: Vec<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub named_arguments: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(default)]
+    pub chunked: bool,
+    // seconds, can only lower the time_limit of the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -26,6 +104,20 @@ pub struct CommandResult {
     pub start_time: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    // the resource limit which killed the command, or the concurrency limit
+    // which refused to run it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<String>,
+    // bytes, only with a cgroup root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_peak: Option<u64>,
+    // seconds of cpu used by the command and its children, only with a cgroup root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_time: Option<f64>,
 }
 
 impl CommandResult {
@@ -43,6 +135,11 @@ impl CommandResult {
             time_cost: Some(time_cost),
             start_time: Some(start_time),
             error: None,
+            truncated: None,
+            signal: None,
+            limit: None,
+            memory_peak: None,
+            cpu_time: None,
         }
     }
 
@@ -54,6 +151,11 @@ impl CommandResult {
             time_cost: Some(time_cost),
             start_time: Some(start_time),
             error: None,
+            truncated: None,
+            signal: None,
+            limit: None,
+            memory_peak: None,
+            cpu_time: None,
         }
     }
 
@@ -65,9 +167,78 @@ impl CommandResult {
             time_cost: None,
             start_time: None,
             error: Some(err),
+            truncated: None,
+            signal: None,
+            limit: None,
+            memory_peak: None,
+            cpu_time: None,
+        }
+    }
+
+    // output printed before the command timed out is kept for debugging
+    pub fn timeout(
+        err: String,
+        stdout: Option<String>,
+        stderr: Option<String>,
+        signal: String,
+        time_cost: f64,
+        start_time: f64,
+    ) -> Self {
+        CommandResult {
+            stdout: stdout,
+            stderr: stderr,
+            exit_code: None,
+            time_cost: Some(time_cost),
+            start_time: Some(start_time),
+            error: Some(err),
+            truncated: None,
+            signal: Some(signal),
+            limit: None,
+            memory_peak: None,
+            cpu_time: None,
         }
     }
 
+    // killed by the kernel for exceeding a resource limit of the command
+    pub fn limit_exceeded(
+        limit: String,
+        stdout: Option<String>,
+        stderr: Option<String>,
+        signal: String,
+        time_cost: f64,
+        start_time: f64,
+    ) -> Self {
+        let mut ret = CommandResult::timeout(
+            format!("Resource Limit Exceeded: {}", limit),
+            stdout,
+            stderr,
+            signal,
+            time_cost,
+            start_time,
+        );
+        ret.limit = Some(limit);
+        ret
+    }
+
+    // not run as no slot was free within the queue_timeout
+    pub fn rejected(err: String, limit: String) -> Self {
+        let mut ret = CommandResult::err(err);
+        ret.limit = Some(limit);
+        ret
+    }
+
+    // mark output as truncated by max_output_bytes
+    pub fn set_truncated(self: &mut Self, truncated: bool) {
+        if truncated {
+            self.truncated = Some(true);
+        }
+    }
+
+    pub fn set_usage(self: &mut Self, memory_peak: Option<u64>, cpu_time: Option<f64>) {
+        self.memory_peak = memory_peak;
+        self.cpu_time = cpu_time;
+    }
+
     pub fn to_json(self: &Self) -> String {
         serde_json::to_string(self).unwrap()
     }
@@ -0,0 +1,234 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use nix::sys::signal;
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::{getpid, Pid};
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio::task;
+use tokio::time;
+
+lazy_static! {
+    // commands spawned by us, which are waited for by tokio and must never
+    // be reaped here
+    static ref CHILDREN: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+}
+
+// set for every command, so adopted orphans can be traced to their run
+static RUN_ID: &str = "REDARROW_RUN_ID";
+
+// makes run ids unique within the process
+static SEQ: AtomicUsize = AtomicUsize::new(0);
+
+// adopt orphaned descendants of commands and reap them, must be called
+// within the runtime
+pub(crate) fn set_subreaper() -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        return Err(anyhow!("PR_SET_CHILD_SUBREAPER: {}", io::Error::last_os_error()));
+    }
+    let mut sigchld = unix_signal(SignalKind::child())?;
+    tokio::spawn(async move {
+        while sigchld.recv().await.is_some() {
+            // NOTE: walking /proc must not block the runtime
+            if let Err(e) = task::spawn_blocking(reap_orphans).await {
+                log::warn!("reap orphans failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// spawn a command, registering it so it is not taken for an orphan
+pub(crate) fn spawn(
+    mut command: process::Command,
+    descendants: Option<&mut Descendants>,
+) -> io::Result<(tokio::process::Child, ChildGuard)> {
+    if let Some(d) = &descendants {
+        command.env(RUN_ID, &d.id);
+    }
+    // NOTE: hold the lock over spawn, or a command exiting at once could be
+    // reaped before it is registered
+    let mut children = CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+    let child = tokio::process::Command::from(command).kill_on_drop(true).spawn()?;
+    let pid = child.id().ok_or(io::Error::new(io::ErrorKind::Other, "spawn error"))? as i32;
+    children.insert(pid);
+    if let Some(d) = descendants {
+        d.root = pid;
+    }
+    Ok((child, ChildGuard(pid)))
+}
+
+pub(crate) struct ChildGuard(i32);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        CHILDREN.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.0);
+    }
+}
+
+fn reap_orphans() {
+    let me = getpid().as_raw();
+    // NOTE: the lock is only taken per zombie, so spawning is never held up
+    // by the walk, while a child spawned meanwhile is already registered
+    for p in processes() {
+        if p.ppid != me || !p.zombie {
+            continue;
+        }
+        let children = CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+        if children.contains(&p.pid) {
+            continue;
+        }
+        if let Err(e) = waitpid(Pid::from_raw(p.pid), Some(WaitPidFlag::WNOHANG)) {
+            log::warn!("reap orphan {} failed: {}", p.pid, e);
+        }
+    }
+}
+
+// every process started by a command: its session, children of known ones
+// and adopted orphans carrying the run id, /proc is only read on blocking
+// threads
+#[derive(Clone)]
+pub(crate) struct Descendants {
+    id: String,
+    root: i32,
+    // pid to start time, so reused pids are never killed
+    pids: Arc<Mutex<HashMap<i32, u64>>>,
+}
+
+impl Descendants {
+    pub(crate) fn new() -> Self {
+        Descendants {
+            id: format!("{}-{}", process::id(), SEQ.fetch_add(1, Ordering::Relaxed)),
+            root: 0,
+            pids: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn refresh(self: &Self) {
+        let all = processes();
+        let mut pids = self.pids.lock().unwrap_or_else(|e| e.into_inner());
+        let me = getpid().as_raw();
+        let marker = format!("{}={}", RUN_ID, self.id);
+        let starts: HashMap<i32, u64> = all.iter().map(|p| (p.pid, p.start_time)).collect();
+        loop {
+            let mut found = false;
+            for p in all.iter() {
+                if pids.get(&p.pid) == Some(&p.start_time) || p.zombie {
+                    continue;
+                }
+                let known = p.pid == self.root
+                    || p.session == self.root
+                    || starts
+                        .get(&p.ppid)
+                        .map_or(false, |s| pids.get(&p.ppid) == Some(s))
+                    || (p.ppid == me && has_env(p.pid, &marker));
+                if known {
+                    pids.insert(p.pid, p.start_time);
+                    found = true;
+                }
+            }
+            if !found {
+                return;
+            }
+        }
+    }
+
+    // keep track of descendants while the command runs, never returns
+    pub(crate) async fn watch(self: &Self) {
+        let mut interval = time::interval(Duration::from_millis(250));
+        loop {
+            interval.tick().await;
+            let d = self.clone();
+            if let Err(e) = task::spawn_blocking(move || d.refresh()).await {
+                log::warn!("track descendants of {} failed: {}", self.root, e);
+            }
+        }
+    }
+
+    pub(crate) async fn kill(self: &Self) {
+        let d = self.clone();
+        if let Err(e) = task::spawn_blocking(move || d.kill_blocking()).await {
+            log::warn!("kill descendants of {} failed: {}", self.root, e);
+        }
+    }
+
+    // kill from outside the runtime or a blocking thread
+    pub(crate) fn kill_blocking(self: &Self) {
+        self.refresh();
+        let alive: HashMap<i32, u64> = processes()
+            .into_iter()
+            .filter(|p| !p.zombie)
+            .map(|p| (p.pid, p.start_time))
+            .collect();
+        let pids = self.pids.lock().unwrap_or_else(|e| e.into_inner());
+        for (pid, start_time) in pids.iter() {
+            if alive.get(pid) == Some(start_time) {
+                let _ = signal::kill(Pid::from_raw(*pid), signal::SIGKILL);
+            }
+        }
+    }
+}
+
+fn has_env(pid: i32, var: &str) -> bool {
+    match fs::read(format!("/proc/{}/environ", pid)) {
+        Ok(environ) => environ.split(|b| *b == 0).any(|v| v == var.as_bytes()),
+        Err(_) => false,
+    }
+}
+
+struct Process {
+    pid: i32,
+    ppid: i32,
+    session: i32,
+    zombie: bool,
+    start_time: u64,
+}
+
+fn processes() -> Vec<Process> {
+    let entries = match fs::read_dir("/proc") {
+        Ok(e) => e,
+        Err(e) => {
+            log::warn!("read /proc failed: {}", e);
+            return Vec::new();
+        }
+    };
+    entries
+        .filter_map(|e| e.ok()?.file_name().to_str()?.parse::<i32>().ok())
+        .filter_map(|pid| parse_stat(pid, &fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?))
+        .collect()
+}
+
+fn parse_stat(pid: i32, stat: &str) -> Option<Process> {
+    // NOTE: comm is in parentheses and may contain anything
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    Some(Process {
+        pid: pid,
+        ppid: fields.get(1)?.parse().ok()?,
+        session: fields.get(3)?.parse().ok()?,
+        zombie: *fields.get(0)? == "Z",
+        start_time: fields.get(19)?.parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "42 (a) b (c)) S 1 42 42 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 12345 0 0";
+        let p = parse_stat(42, stat).unwrap();
+        assert_eq!(p.ppid, 1);
+        assert_eq!(p.session, 42);
+        assert!(!p.zombie);
+        assert_eq!(p.start_time, 12345);
+        assert!(parse_stat(42, "42 (a) S 1").is_none());
+    }
+}
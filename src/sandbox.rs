@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use anyhow::{anyhow, Result};
+
+use crate::dispatcher::parse_bool;
+
+// namespaces and mounts of an untrusted command, set up in pre_exec
+#[derive(Debug, Clone)]
+pub(crate) struct Sandbox {
+    // keep the network of the host instead of an empty namespace
+    network: bool,
+    read_only: Vec<CString>,
+}
+
+impl Sandbox {
+    pub(crate) fn parse(prop: &HashMap<String, String>) -> Result<Option<Self>> {
+        match prop.get("sandbox") {
+            Some(v) if parse_bool(v)? => {}
+            _ => return Ok(None),
+        }
+        let network = match prop.get("sandbox.network") {
+            Some(v) => parse_bool(v)?,
+            None => false,
+        };
+        let mut read_only = Vec::new();
+        if let Some(paths) = prop.get("sandbox.read_only") {
+            for path in paths.split(",").map(|p| p.trim()).filter(|p| *p != "") {
+                if !Path::new(path).is_absolute() || !Path::new(path).exists() {
+                    return Err(anyhow!("sandbox.read_only {} not found", path));
+                }
+                read_only.push(CString::new(path)?);
+            }
+        }
+        Ok(Some(Sandbox {
+            network: network,
+            read_only: read_only,
+        }))
+    }
+
+    // NOTE: a new pid namespace only applies to children, so the process
+    // forks here and stays as a supervisor forwarding the exit status, while
+    // the child becomes pid 1 of the sandbox and goes on to exec, keep is
+    // a file under /tmp which stays visible, like an inline script, and
+    // forward the kill signal the supervisor passes on to the sandbox
+    pub(crate) fn enter(
+        self: &Self,
+        keep: Option<&CStr>,
+        setup: &Setup,
+        forward: libc::c_int,
+    ) -> io::Result<()> {
+        setup.set(SETUP_STARTED);
+        let mut flags = libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+        if !self.network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        unsafe {
+            check(libc::unshare(flags))?;
+            match check(libc::fork())? {
+                0 => {}
+                pid => supervise(pid, forward),
+            }
+            // the whole sandbox goes down with the supervisor
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0))?;
+            check(libc::mount(
+                ptr::null(),
+                c("/\0"),
+                ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                ptr::null(),
+            ))?;
+            check(libc::mount(
+                c("proc\0"),
+                c("/proc\0"),
+                c("proc\0"),
+                libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                ptr::null(),
+            ))?;
+            let kept = match keep {
+                Some(path) => check(libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC))?,
+                None => -1,
+            };
+            check(libc::mount(
+                c("tmpfs\0"),
+                c("/tmp\0"),
+                c("tmpfs\0"),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                c("mode=1777\0") as *const libc::c_void,
+            ))?;
+            if let Some(path) = keep {
+                let fd = check(libc::open(
+                    path.as_ptr(),
+                    libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC,
+                    0o600,
+                ))?;
+                libc::close(fd);
+                let mut buf = *b"/proc/self/fd/\0\0\0\0\0\0\0\0\0\0\0";
+                fd_path(&mut buf, kept);
+                check(libc::mount(
+                    buf.as_ptr() as *const libc::c_char,
+                    path.as_ptr(),
+                    ptr::null(),
+                    libc::MS_BIND,
+                    ptr::null(),
+                ))?;
+                libc::close(kept);
+            }
+            for path in self.read_only.iter() {
+                check(libc::mount(
+                    path.as_ptr(),
+                    path.as_ptr(),
+                    ptr::null(),
+                    libc::MS_BIND | libc::MS_REC,
+                    ptr::null(),
+                ))?;
+                remount_read_only(path)?;
+            }
+        }
+        setup.set(SETUP_DONE);
+        Ok(())
+    }
+}
+
+const SETUP_STARTED: u8 = 1;
+const SETUP_DONE: u8 = 2;
+
+// progress of the sandbox setup in memory shared with the child, so a
+// failing spawn can be told apart from a sandbox which could not be set up
+#[derive(Debug)]
+pub(crate) struct Setup {
+    addr: usize,
+}
+
+impl Setup {
+    pub(crate) fn new() -> io::Result<Self> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                1,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Setup { addr: addr as usize })
+    }
+
+    fn set(self: &Self, state: u8) {
+        unsafe { ptr::write_volatile(self.addr as *mut u8, state) }
+    }
+
+    // the setup was started but never finished
+    pub(crate) fn failed(self: &Self) -> bool {
+        unsafe { ptr::read_volatile(self.addr as *const u8) == SETUP_STARTED }
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, 1) };
+    }
+}
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+// make a bind mount read-only with everything mounted below it
+unsafe fn remount_read_only(path: &CStr) -> io::Result<()> {
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_RDONLY,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    let ret = libc::syscall(
+        libc::SYS_mount_setattr,
+        libc::AT_FDCWD,
+        path.as_ptr(),
+        libc::AT_RECURSIVE,
+        &attr as *const MountAttr,
+        std::mem::size_of::<MountAttr>(),
+    );
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::ENOSYS) {
+        return Err(err);
+    }
+    // NOTE: before linux 5.12 a remount only applies to the mount itself, so
+    // submounts stay writable there
+    check(libc::mount(
+        ptr::null(),
+        path.as_ptr(),
+        ptr::null(),
+        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+        ptr::null(),
+    ))?;
+    Ok(())
+}
+
+// append fd to the /proc/self/fd/ prefix without allocating
+fn fd_path(buf: &mut [u8; 25], fd: libc::c_int) {
+    let mut digits = [0u8; 10];
+    let (mut n, mut len) = (fd as u32, 0);
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    for i in 0..len {
+        buf[14 + i] = digits[len - 1 - i];
+    }
+}
+
+fn c(s: &'static str) -> *const libc::c_char {
+    s.as_ptr() as *const libc::c_char
+}
+
+fn check<T: Default + PartialOrd>(ret: T) -> io::Result<T> {
+    if ret < T::default() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+// pid 1 of the sandbox, for the handler of the supervisor
+static SANDBOX_PID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    unsafe { libc::kill(SANDBOX_PID.load(Ordering::Relaxed), sig) };
+}
+
+// wait for the sandbox and exit the same way, never returns
+unsafe fn supervise(pid: libc::pid_t, forward: libc::c_int) -> ! {
+    // NOTE: handlers of the server are still installed as nothing is exec'd,
+    // while the kill signal is passed on, so the command gets its grace and
+    // only SIGKILL takes the supervisor and with it the sandbox down
+    for sig in 1..32 {
+        libc::signal(sig, libc::SIG_DFL);
+    }
+    SANDBOX_PID.store(pid, Ordering::Relaxed);
+    if forward != libc::SIGKILL {
+        libc::signal(forward, forward_signal as libc::sighandler_t);
+    }
+    // NOTE: the error pipe of spawn must not be held open until the command
+    // exits, so everything but stdio is closed
+    if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) != 0 {
+        for fd in 3..1024 {
+            libc::close(fd);
+        }
+    }
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) < 0 {
+        if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            libc::_exit(1);
+        }
+    }
+    if libc::WIFSIGNALED(status) {
+        let sig = libc::WTERMSIG(status);
+        libc::signal(sig, libc::SIG_DFL);
+        libc::kill(libc::getpid(), sig);
+        libc::_exit(128 + sig);
+    }
+    libc::_exit(libc::WEXITSTATUS(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fd_path() {
+        let mut buf = *b"/proc/self/fd/\0\0\0\0\0\0\0\0\0\0\0";
+        fd_path(&mut buf, 1023);
+        let path = CStr::from_bytes_until_nul(&buf[..]).unwrap();
+        assert_eq!(path.to_str().unwrap(), "/proc/self/fd/1023");
+    }
+
+    #[test]
+    fn test_setup_failed() {
+        use std::os::unix::process::CommandExt;
+        use std::sync::Arc;
+
+        let setup = Arc::new(Setup::new().unwrap());
+        assert!(!setup.failed());
+        let progress = setup.clone();
+        let mut command = std::process::Command::new("/bin/true");
+        unsafe {
+            command.pre_exec(move || {
+                progress.set(SETUP_STARTED);
+                Err(io::Error::from_raw_os_error(libc::EPERM))
+            });
+        }
+        assert!(command.spawn().is_err());
+        assert!(setup.failed());
+    }
+}
@@ -1,5 +1,6 @@
+use std::collections::HashMap;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 use std::task::{Context, Poll};
 
 use argh::FromArgs;
@@ -11,8 +12,14 @@ use warp::http::StatusCode;
 use warp::Filter;
 use warp::{Rejection, Reply};
 
-use redarrow::dispatcher::{read_config, Command, Configs, RedarrowWaker};
-use redarrow::{CommandParams, CommandResult};
+use redarrow::dispatcher::{
+    apply_configs, read_config, set_cgroup_root, set_max_concurrency, set_subreaper, Busy,
+    Command, Configs, Slot, CONFIG_RELOADS,
+};
+use redarrow::{CommandParams, CommandRequest, CommandResult};
+
+// limit for the json body of POST /command
+const MAX_REQUEST_BODY: u64 = 1024 * 1024;
 
 #[argh(description = "execute command for remote redarrow client")]
 #[derive(FromArgs, Debug)]
@@ -37,17 +44,58 @@ struct ServerArgs {
         option,
         short = 'w',
         default = "4",
-        description = "number of worker processes for handling requests"
+        description = "number of worker threads for handling requests"
     )]
     workers: usize,
+
+    #[argh(
+        option,
+        description = "writable cgroup v2 directory to run each command in its own group"
+    )]
+    cgroup_root: Option<String>,
+
+    #[argh(
+        option,
+        description = "max commands running at the same time, further requests wait for the queue_timeout of their command"
+    )]
+    max_concurrency: Option<usize>,
 }
 
-#[tokio::main]
-async fn main() {
-    let metric_route = warp::path!("metrics").and_then(metrics_handler);
+fn main() {
     pretty_env_logger::init_timed();
 
     let args: ServerArgs = argh::from_env();
+    let rt = match tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(args.workers.max(1))
+        .enable_all()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(e) => {
+            log::error!("build runtime error: {}", e);
+            return;
+        }
+    };
+    rt.block_on(serve(args))
+}
+
+async fn serve(args: ServerArgs) {
+    let metric_route = warp::path!("metrics").and_then(metrics_handler);
+    if let Err(e) = set_subreaper() {
+        log::warn!("subreaper error: {}", e);
+    }
+    if let Some(root) = &args.cgroup_root {
+        if let Err(e) = set_cgroup_root(root) {
+            log::error!("cgroup root error: {}", e);
+            return;
+        }
+    }
+    if let Some(max) = args.max_concurrency {
+        if let Err(e) = set_max_concurrency(max) {
+            log::error!("max concurrency error: {}", e);
+            return;
+        }
+    }
     let configs = match read_config(args.config.as_str()) {
         Ok(c) => {
             log::info!("parsed {} commands, starting server...", &c.len());
@@ -58,8 +106,18 @@ async fn main() {
             return;
         }
     };
-    let configs = Arc::new(configs);
-    let configs = warp::any().map(move || configs.clone());
+    apply_configs(&configs);
+    // NOTE: requests hold a snapshot of the configs, so reloading
+    // never affects commands already running
+    let configs = Arc::new(RwLock::new(Arc::new(configs)));
+    let reload_configs = configs.clone();
+    let configs = warp::any().map(move || match configs.read() {
+        Ok(c) => c.clone(),
+        Err(e) => e.into_inner().clone(),
+    });
+    let params = warp::query::<CommandParams>()
+        .and(warp::query::<Vec<(String, String)>>())
+        .map(|opts: CommandParams, query| opts.with_query(query));
 
     let (tx, mut rx) = mpsc::channel::<&str>(2);
 
@@ -68,17 +126,22 @@ async fn main() {
             warp::path("command")
             .and(warp::get())
             .and(warp::path::param::<String>())
-            .and(warp::query::<CommandParams>())
-            .and(configs)
+            .and(params)
+            .and(configs.clone())
             .and_then(handlers_command)
+            .or(warp::path("command")
+                .and(warp::path::end())
+                .and(warp::post())
+                .and(warp::body::content_length_limit(MAX_REQUEST_BODY))
+                .and(warp::body::json::<CommandRequest>())
+                .and(configs)
+                .and_then(run_command))
             .with(warp::log("redarrow::http"))),
     )
     .bind_with_graceful_shutdown(([0, 0, 0, 0], args.port), async move {
         while let Some(res) = rx.recv().await {
             match res {
                 "TERM" => break,
-                // TODO:(everpcpc) impl reload
-                "HUP" => break,
                 _ => log::error!("received invalid signal: {}", res),
             }
         }
@@ -87,12 +150,12 @@ async fn main() {
     log::info!("listening on {}", addr);
 
     let mut stream_hup = signal(SignalKind::hangup()).unwrap();
-    let hup_tx = tx.clone();
+    let config_file = args.config.clone();
     tokio::task::spawn(async move {
         loop {
             stream_hup.recv().await;
             log::info!("SIGHUP received. Reloading...");
-            hup_tx.send("HUP").await.unwrap();
+            reload_config(config_file.as_str(), &reload_configs);
         }
     });
     let mut stream_term = signal(SignalKind::terminate()).unwrap();
@@ -106,20 +169,46 @@ async fn main() {
     tokio::task::spawn(server).await.unwrap()
 }
 
+fn reload_config(config_file: &str, configs: &RwLock<Arc<Configs>>) {
+    match read_config(config_file) {
+        Ok(c) => {
+            log::info!("reloaded {} commands", &c.len());
+            let c = Arc::new(c);
+            match configs.write() {
+                Ok(mut current) => *current = c.clone(),
+                Err(e) => *e.into_inner() = c.clone(),
+            }
+            apply_configs(&c);
+            CONFIG_RELOADS.with_label_values(&["ok"]).inc();
+        }
+        Err(e) => {
+            log::error!("reload config error, keep serving the old one: {}", e);
+            CONFIG_RELOADS.with_label_values(&["error"]).inc();
+        }
+    }
+}
+
 async fn handlers_command(
     command: String,
     opts: CommandParams,
     configs: Arc<Configs>,
 ) -> Result<Box<dyn warp::Reply>, std::convert::Infallible> {
-    let chunked: bool = match opts.chunked {
-        None => false,
-        Some(c) => c != 0,
-    };
-    let arguments = match &opts.argument {
-        None => Vec::new(),
-        Some(a) => a.split(" ").map(|x| x.to_string()).collect(),
-    };
-    let format: String = match &opts.format {
+    run_command(opts.into_request(command), configs).await
+}
+
+async fn run_command(
+    req: CommandRequest,
+    configs: Arc<Configs>,
+) -> Result<Box<dyn warp::Reply>, std::convert::Infallible> {
+    let CommandRequest {
+        command,
+        arguments,
+        named_arguments,
+        format,
+        chunked,
+        timeout,
+    } = req;
+    let format: String = match &format {
         None => "json".to_string(),
         Some(f) if f == "json" => "json".to_string(),
         Some(f) if f == "prometheus" => "prometheus".to_string(),
@@ -147,10 +236,20 @@ async fn handlers_command(
             }
         }
         Some(cmd) => {
+            let mut cmd = cmd.clone();
+            if let Some(t) = timeout {
+                cmd.limit_time(t);
+            }
+            let slot = match cmd.acquire().await {
+                Ok(slot) => slot,
+                Err(busy) => return Ok(reply_busy(busy, chunked)),
+            };
             if chunked {
-                handle_command_chunked(cmd.clone(), arguments)
+                handle_command_chunked(cmd, arguments, named_arguments, slot)
             } else {
-                match cmd.execute(arguments) {
+                let ret = cmd.execute(arguments, named_arguments).await;
+                drop(slot);
+                match ret {
                     Err(e) => Ok(Box::new(warp::reply::with_status(
                         warp::reply::json(&CommandResult::err(format!("{}", e))),
                         StatusCode::INTERNAL_SERVER_ERROR,
@@ -169,59 +268,46 @@ async fn handlers_command(
     }
 }
 
+// a command limit is up to the client to back off, a server limit is not
+fn reply_busy(busy: Busy, chunked: bool) -> Box<dyn warp::Reply> {
+    let status = match busy {
+        Busy::Command(_) => StatusCode::TOO_MANY_REQUESTS,
+        Busy::Server(_) => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let err = CommandResult::rejected(busy.to_string(), busy.limit().to_string());
+    if chunked {
+        Box::new(warp::reply::with_status(format!("0> {}\n", err.to_json()), status))
+    } else {
+        Box::new(warp::reply::with_status(warp::reply::json(&err), status))
+    }
+}
+
+// lines buffered for a chunked response before the command is paused
+const CHUNKED_BUFFER: usize = 64;
+
 fn handle_command_chunked(
     cmd: Command,
     arguments: Vec<String>,
+    named: HashMap<String, String>,
+    slot: Slot,
 ) -> Result<Box<dyn warp::Reply>, std::convert::Infallible> {
-    let (tx_cmd, rx_cmd) = std::sync::mpsc::channel::<String>();
-    let waker = Arc::new(Mutex::new(RedarrowWaker::new()));
-    let mut wake_sender = waker.clone();
-    let _child = std::thread::spawn(move || {
+    let (tx_cmd, rx_cmd) = mpsc::channel::<String>(CHUNKED_BUFFER);
+    tokio::task::spawn(async move {
         let ret = format!(
             "0> {}\n",
-            match cmd.execute_iter(arguments, tx_cmd.clone(), &mut wake_sender) {
+            match cmd.execute_iter(arguments, named, &tx_cmd).await {
                 Ok(r) => r,
                 Err(e) => CommandResult::err(format!("{}", e)),
             }
             .to_json()
         );
-        match tx_cmd.send(ret) {
-            Err(e) => {
-                log::warn!("send command result error: {}", e);
-                return;
-            }
-            Ok(()) => {
-                if let Ok(mut waker) = wake_sender.lock() {
-                    waker.wake();
-                } else {
-                    log::warn!("waker on command result failed to get lock");
-                }
-            }
-        }
-        match tx_cmd.send("\0\0".to_string()) {
-            Err(e) => {
-                log::warn!("send command end error: {}", e);
-                return;
-            }
-            Ok(()) => {
-                if let Ok(mut waker) = wake_sender.lock() {
-                    waker.wake();
-                } else {
-                    log::warn!("waker on command end failed to get lock");
-                }
-            }
-        }
-        // wake last in case magic end word not received
-        if let Ok(mut waker) = wake_sender.lock() {
-            waker.wake();
-        } else {
-            log::warn!("waker last failed to get lock");
+        drop(slot);
+        // NOTE: the response ends when tx_cmd is dropped
+        if let Err(e) = tx_cmd.send(ret).await {
+            log::warn!("send command result error: {}", e);
         }
     });
-    let r = ChunkedResponse {
-        rx: rx_cmd,
-        waker: waker,
-    };
+    let r = ChunkedResponse { rx: rx_cmd };
     let mut res = hyper::Response::new(hyper::Body::empty());
     *res.body_mut() = hyper::Body::wrap_stream(r);
     Ok(Box::new(res))
@@ -229,33 +315,14 @@ fn handle_command_chunked(
 
 #[derive(Debug)]
 struct ChunkedResponse {
-    rx: std::sync::mpsc::Receiver<String>,
-    waker: Arc<Mutex<RedarrowWaker>>,
+    rx: mpsc::Receiver<String>,
 }
 
-unsafe impl Sync for ChunkedResponse {}
-
 impl Stream for ChunkedResponse {
     type Item = Result<String, warp::Error>;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.rx.try_recv() {
-            Err(_) => {
-                if let Ok(mut waker) = self.waker.lock() {
-                    waker.register(cx.waker());
-                } else {
-                    log::warn!("register waker failed to get lock");
-                }
-                Poll::Pending
-            }
-            Ok(result) => {
-                if result == "\0\0" {
-                    Poll::Ready(None)
-                } else {
-                    Poll::Ready(Some(Ok(result)))
-                }
-            }
-        }
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx).map(|r| r.map(Ok))
     }
 }
 
@@ -1,9 +1,11 @@
+use std::collections::HashMap;
 use std::sync::mpsc;
 use std::time::Duration;
 
 use anyhow::Result;
+use reqwest::StatusCode;
 
-use crate::{CommandParams, CommandResult};
+use crate::{CommandParams, CommandRequest, CommandResult};
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
@@ -13,8 +15,10 @@ pub struct Client {
     port: u32,
     command: String,
     arguments: Vec<String>,
+    named_arguments: HashMap<String, String>,
     user_agent: String,
     connect_timeout: Duration,
+    timeout: Option<u64>,
 }
 
 impl Client {
@@ -24,8 +28,10 @@ impl Client {
             port: port,
             command: command,
             arguments: arguments,
+            named_arguments: HashMap::new(),
             user_agent: format!("Redarrow-webclient/{}", VERSION),
             connect_timeout: Duration::new(3, 0),
+            timeout: None,
         }
     }
 
@@ -37,13 +43,28 @@ impl Client {
         self.connect_timeout = timeout;
     }
 
+    // arguments filled into ${name} placeholders of the command
+    pub fn set_named_arguments(self: &mut Self, named: HashMap<String, String>) {
+        self.named_arguments = named;
+    }
+
+    // ask the server to stop the command earlier than its time_limit
+    pub fn set_timeout(self: &mut Self, timeout: u64) {
+        self.timeout = Some(timeout);
+    }
+
     fn build_url(self: &Self) -> String {
+        format!("http://{}:{}/command", self.host, self.port)
+    }
+
+    fn build_get_url(self: &Self) -> String {
         format!(
             "http://{}:{}/command/{}",
             self.host, self.port, self.command
         )
     }
 
+    // NOTE: the space joined form is still sent for servers without `arg` support
     fn get_arguments(self: &Self) -> Option<String> {
         if self.arguments.is_empty() {
             None
@@ -52,22 +73,54 @@ impl Client {
         }
     }
 
-    pub async fn run_command(self: &Self) -> Result<CommandResult> {
-        let params = CommandParams {
-            chunked: None,
+    fn build_params(self: &Self, chunked: bool) -> CommandParams {
+        CommandParams {
+            chunked: if chunked { Some(1) } else { None },
             argument: self.get_arguments(),
             format: None,
-        };
-        let body = reqwest::Client::builder()
+            arg: self.arguments.clone(),
+            named: self.named_arguments.clone(),
+        }
+    }
+
+    fn build_request(self: &Self, chunked: bool) -> CommandRequest {
+        CommandRequest {
+            command: self.command.clone(),
+            arguments: self.arguments.clone(),
+            named_arguments: self.named_arguments.clone(),
+            format: None,
+            chunked: chunked,
+            timeout: self.timeout,
+        }
+    }
+
+    // post the request, falling back to GET /command/<name> for servers
+    // without POST /command, which do not know the timeout either
+    async fn send(self: &Self, chunked: bool) -> Result<reqwest::Response> {
+        let client = reqwest::Client::builder()
             .user_agent(self.user_agent.as_str())
             .connect_timeout(self.connect_timeout)
-            .build()?
-            .get(self.build_url().as_str())
-            .query(&params)
+            .build()?;
+        let res = client
+            .post(self.build_url().as_str())
+            .json(&self.build_request(chunked))
             .send()
-            .await?
-            .bytes()
             .await?;
+        match res.status() {
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => {}
+            _ => return Ok(res),
+        }
+        let params = self.build_params(chunked);
+        Ok(client
+            .get(self.build_get_url().as_str())
+            .query(&params)
+            .query(&params.to_query())
+            .send()
+            .await?)
+    }
+
+    pub async fn run_command(self: &Self) -> Result<CommandResult> {
+        let body = self.send(false).await?.bytes().await?;
         Ok(serde_json::from_slice(&body)?)
     }
 
@@ -75,19 +128,7 @@ impl Client {
         self: &Self,
         tx: mpsc::Sender<(i8, Vec<u8>)>,
     ) -> Result<CommandResult> {
-        let params = CommandParams {
-            chunked: Some(1),
-            argument: self.get_arguments(),
-            format: None,
-        };
-        let mut res = reqwest::Client::builder()
-            .user_agent(self.user_agent.as_str())
-            .connect_timeout(self.connect_timeout)
-            .build()?
-            .get(self.build_url().as_str())
-            .query(&params)
-            .send()
-            .await?;
+        let mut res = self.send(true).await?;
 
         let mut last_fd = -1;
         let mut tmp: Vec<u8> = Vec::new();